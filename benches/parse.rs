@@ -0,0 +1,50 @@
+// Telegrams per second through the full parse path, from the raw telegram to the measurements
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use dsmr_influxdb_adapter::config::Config;
+use dsmr_influxdb_adapter::obis::ObisValues;
+use dsmr_influxdb_adapter::parse;
+use dsmr_influxdb_adapter::stats::Stats;
+use futures::executor::block_on;
+use std::fs;
+
+// The captured telegrams of the Dutch and Belgian meters, the DSMR profile
+const FIXTURES: &[&str] = &[
+    "fixtures/dsmr42.txt",
+    "fixtures/dsmr4-gas-slot.txt",
+    "fixtures/dsmr3.txt",
+    "fixtures/fluvius.txt",
+];
+
+fn parse_measurements(config: &Config, stats: &mut Stats, telegram: &str) -> usize {
+    let obis = ObisValues::parse(telegram);
+    block_on(parse(config, stats, &obis)).measurements().count()
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let config = Config::default();
+    let mut stats = Stats::default();
+    let sample = fs::read_to_string(FIXTURES[0]).unwrap();
+    let batch: Vec<String> = FIXTURES
+        .iter()
+        .map(|_fixture| fs::read_to_string(_fixture).unwrap())
+        .collect();
+
+    let mut group = c.benchmark_group("parse_telegram");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("sample", |b| {
+        b.iter(|| parse_measurements(&config, &mut stats, &sample))
+    });
+    group.throughput(Throughput::Elements(batch.len() as u64));
+    group.bench_function("fixtures", |b| {
+        b.iter(|| {
+            batch
+                .iter()
+                .map(|_telegram| parse_measurements(&config, &mut stats, _telegram))
+                .sum::<usize>()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
@@ -0,0 +1,1284 @@
+use crate::config::{Aggregate, Config};
+use crate::influx::{line_protocol, post_influx_db};
+use crate::measurements::{
+    is_measurement_selected, ACCUMULATIVE_MEASUREMENTS, DERIVED_MEASUREMENTS, GAS_MEASUREMENTS,
+    INSTANTANEOUS_MEASUREMENTS, LIVENESS_MEASUREMENTS,
+};
+use crate::metrics;
+use crate::obis::ObisValues;
+use crate::parse::{
+    float_measurement, parse, parse_power_failure_log, parse_protocol_version, print_obis_dump,
+    round_kwh, GAS_UNIT,
+};
+use crate::state::State;
+use crate::stats::Stats;
+use crate::telegram::{system_timestamp, Telegram};
+use crate::{crc, graphite, health, FieldValue};
+use futures::future;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::Instrument;
+
+// Time between two writes of the `--state-file`, to spare e.g. the SD card of a Raspberry Pi
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Accumulative counters and the measurement `--post-deltas` posts their usage since the previous
+// post as
+const DELTA_MEASUREMENTS: [(&str, &str); 2] = [
+    ("wattUsageAccumulative", "energyUsedDelta"),
+    ("gasUsageAccumulative", "gasUsedDelta"),
+];
+
+// Time after which a gas reading is stale, `--gas-stale-after` or twice the interval the gas
+// meter is read at: every 5 minutes by DSMR 5 meters, every hour by older meters
+async fn gas_stale_after(config: &Config, obis: &ObisValues<'_>) -> Duration {
+    match (config.gas_stale_after, parse_protocol_version(obis).await) {
+        (Some(_gas_stale_after), _) => _gas_stale_after,
+        (None, Ok(_version)) if _version >= 50 => Duration::from_secs(10 * 60),
+        (None, _) => Duration::from_secs(2 * 60 * 60),
+    }
+}
+
+// Whether a measurement is among the measurements of a telegram
+fn has_measurement(measurements: &[(&'static str, FieldValue)], name: &str) -> bool {
+    measurements.iter().any(|(key, _)| *key == name)
+}
+
+// Holds on to the latest parsed telegram until the posting interval has elapsed
+struct IntervalBuffer {
+    interval: Option<Duration>,
+    aggregate: Aggregate,
+    last_post: Option<Instant>,
+    // Running sum, sample count and timestamp of the last sample per instantaneous measurement,
+    // used for `Aggregate::Mean`
+    sums: HashMap<&'static str, (f64, u32, Option<i64>)>,
+}
+
+impl IntervalBuffer {
+    fn new(interval: Option<Duration>, aggregate: Aggregate) -> IntervalBuffer {
+        IntervalBuffer {
+            interval,
+            aggregate,
+            last_post: None,
+            sums: HashMap::new(),
+        }
+    }
+
+    // Store a telegram, returns the telegram to post if the interval has elapsed. Without an
+    // interval every telegram is posted.
+    fn push(&mut self, mut telegram: Telegram) -> Option<Telegram> {
+        if self.aggregate == Aggregate::Mean {
+            for measurement in telegram.measurements() {
+                if let FieldValue::Float(_value) = measurement.value {
+                    if INSTANTANEOUS_MEASUREMENTS.contains(&measurement.name) {
+                        let sum = self.sums.entry(measurement.name).or_insert((0.0, 0, None));
+                        sum.0 += _value;
+                        sum.1 += 1;
+                        sum.2 = measurement.timestamp;
+                    }
+                }
+            }
+        }
+
+        let due = match (self.interval, self.last_post) {
+            (Some(_interval), Some(_last_post)) => _last_post.elapsed() >= _interval,
+            _ => true,
+        };
+        if !due {
+            return None;
+        }
+
+        // Replace the instantaneous values of the latest telegram by their mean. A measurement
+        // the latest telegram doesn't have (e.g. dropped as implausible) is posted at the time
+        // of its last sample.
+        let mut measurements = telegram.take_measurements();
+        for key in INSTANTANEOUS_MEASUREMENTS {
+            if let Some((_sum, _count, _timestamp)) = self.sums.get(key) {
+                let mean = FieldValue::Float(_sum / f64::from(*_count));
+                match measurements.iter_mut().find(|(_key, _)| _key == key) {
+                    Some((_, _value)) => *_value = mean,
+                    None => {
+                        measurements.push((key, mean));
+                        telegram.set_timestamp(key, *_timestamp);
+                    }
+                }
+            }
+        }
+        telegram.set_measurements(measurements);
+        self.sums.clear();
+
+        self.last_post = Some(Instant::now());
+        Some(telegram)
+    }
+}
+
+// Whether a measurement is plausible, see `Adapter::reject_outliers`. Keeps track of the
+// accumulative counters it accepts and rejects.
+fn is_plausible(
+    config: &Config,
+    previous_counters: &mut HashMap<&'static str, (Instant, f64)>,
+    rejected_counters: &mut HashMap<&'static str, f64>,
+    now: Instant,
+    key: &'static str,
+    value: &FieldValue,
+) -> bool {
+    let value = match value {
+        FieldValue::Float(_value) => *_value,
+        _ => return true,
+    };
+
+    if INSTANTANEOUS_MEASUREMENTS.contains(&key) {
+        return match config.max_watt {
+            Some(_max_watt) if value.abs() > _max_watt => {
+                log!("Warning: dropping implausible {} of {} W", key, value);
+                false
+            }
+            _ => true,
+        };
+    }
+
+    if !ACCUMULATIVE_MEASUREMENTS.contains(&key) {
+        return true;
+    }
+    let max_delta = match (key, previous_counters.get(key)) {
+        ("gasUsageAccumulative", Some(_)) => config.max_gas_delta,
+        (_, Some((_previous_time, _))) => config.max_watt.map(|_max_watt| {
+            let hours = now.duration_since(*_previous_time).as_secs_f64() / 3600.0;
+            // W -> kWh, with a margin for the resolution of the counter
+            _max_watt * hours / 1000.0 + 0.001
+        }),
+        (_, None) => None,
+    };
+    if let Some((_, _previous_value)) = previous_counters.get(key) {
+        // Only the nett counter goes down when more is produced than used
+        if value < *_previous_value && key != "wattAccumulativeNett" && !config.allow_counter_reset
+        {
+            log!(
+                "Warning: dropping decreased {} of {} (previous {})",
+                key,
+                value,
+                _previous_value
+            );
+            return false;
+        }
+    }
+    if let (Some(_max_delta), Some((_, _previous_value))) = (max_delta, previous_counters.get(key))
+    {
+        // A corrupted telegram is a one-off, a jump that is still there in the next
+        // telegram is real (e.g. after the connection to the meter was lost for a while)
+        let delta = value - _previous_value;
+        if delta.abs() > _max_delta && rejected_counters.get(key) != Some(&value) {
+            log!(
+                "Warning: dropping implausible {} of {} (previous {})",
+                key,
+                value,
+                _previous_value
+            );
+            rejected_counters.insert(key, value);
+            return false;
+        }
+    }
+    rejected_counters.remove(key);
+    previous_counters.insert(key, (now, value));
+    true
+}
+
+// State that is kept between telegrams
+pub struct Adapter {
+    pub config: Config,
+    client: reqwest::Client,
+    // Connection to the Carbon server with `--graphite`
+    graphite: Option<graphite::Graphite>,
+    interval_buffer: IntervalBuffer,
+    last_telegram: health::LastTelegram,
+    metrics: metrics::Metrics,
+    // Previous gas reading as (timestamp, m3), used to calculate the gas flow rate
+    previous_gas: Option<(i64, f64)>,
+    // Gas flow rate in m3/h between the two most recent gas readings
+    gas_flow_rate: Option<f64>,
+    // Last accumulative usage in kWh, the time it changed and whether it was seen changing
+    previous_usage_accumulative: Option<(Instant, f64, bool)>,
+    // Whether the gas reading of the previous telegram was stale
+    gas_stale: bool,
+    // Unit of the most recent gas reading
+    gas_unit: Option<String>,
+    // Usage in W derived from the two most recent changes of the accumulative usage
+    w_usage_derived: Option<f64>,
+    // End time of the most recent failure in the power failure event log, and the number of
+    // failures added to the log since the adapter started
+    last_failure_end: Option<i64>,
+    failure_events: i64,
+    // Last posted value of each accumulative counter, used by `--dedupe-accumulative`
+    last_posted: HashMap<&'static str, FieldValue>,
+    // Last posted value of each measurement and when it was posted, used by
+    // `--post-on-change-only`
+    posted_on_change: HashMap<&'static str, (Instant, FieldValue)>,
+    // Accumulative counters at the previous post, used by `--post-deltas`
+    delta_counters: HashMap<&'static str, f64>,
+    // Exponential moving average of each instantaneous measurement, used by `--smooth-alpha`
+    smoothed: HashMap<&'static str, f64>,
+    // Last accepted value of each accumulative counter and when it was received, used to reject
+    // implausible jumps
+    previous_counters: HashMap<&'static str, (Instant, f64)>,
+    // Last rejected value of each accumulative counter, a jump that persists is accepted
+    rejected_counters: HashMap<&'static str, f64>,
+    // Next time the state is written to the `--state-file`
+    next_state_save: Instant,
+    // Lines collected for the next flush with `--flush-interval`, and when that is due
+    pending_lines: Vec<String>,
+    next_flush: Instant,
+    pub stats: Stats,
+}
+
+impl Adapter {
+    pub fn new(
+        config: &Config,
+        client: reqwest::Client,
+        last_telegram: health::LastTelegram,
+        metrics: metrics::Metrics,
+    ) -> Adapter {
+        Adapter {
+            interval_buffer: IntervalBuffer::new(config.interval, config.aggregate),
+            config: config.clone(),
+            client,
+            graphite: config
+                .graphite
+                .as_ref()
+                .map(|_graphite| graphite::Graphite::new(_graphite)),
+            last_telegram,
+            metrics,
+            previous_gas: None,
+            gas_flow_rate: None,
+            gas_stale: false,
+            gas_unit: None,
+            previous_usage_accumulative: None,
+            w_usage_derived: None,
+            last_failure_end: None,
+            failure_events: 0,
+            last_posted: HashMap::new(),
+            posted_on_change: HashMap::new(),
+            delta_counters: HashMap::new(),
+            smoothed: HashMap::new(),
+            previous_counters: HashMap::new(),
+            rejected_counters: HashMap::new(),
+            next_state_save: Instant::now(),
+            pending_lines: Vec::new(),
+            next_flush: Instant::now() + config.flush_interval.unwrap_or_default(),
+            stats: Stats::default(),
+        }
+    }
+
+    // Parse a complete telegram and post its measurements, returns whether the telegram was valid
+    pub async fn handle_telegram(&mut self, telegram: &str) -> bool {
+        verbose!("Complete Telegram:");
+        verbose!("{}", telegram);
+        verbose!("\n");
+
+        let obis = &ObisValues::parse(telegram);
+
+        // Only dump the ids, a telegram of an unknown meter likely has no known measurement
+        if self.config.obis_dump {
+            print_obis_dump(self.config.meter_profile, obis);
+            return obis.iter().next().is_some();
+        }
+
+        self.stats.telegrams += 1;
+        // `is_multiple_of` needs Rust 1.87, newer than the toolchains of e.g. Raspberry Pi OS
+        #[allow(clippy::manual_is_multiple_of)]
+        if let Some(_stats_every) = self.config.stats_every {
+            if self.stats.telegrams % _stats_every == 0 {
+                log!("Statistics: {}", self.stats);
+            }
+        }
+
+        // Counted for the statistics, a flaky cable shows up as CRC errors. The telegram is still
+        // parsed, values that can't be parsed are left out as always.
+        if let Err(_err) =
+            tracing::debug_span!("crc").in_scope(|| crc::check(telegram, self.config.meter_profile))
+        {
+            log!("Warning: {}, the telegram may hold garbled values", _err);
+            self.stats.crc_errors += 1;
+        }
+
+        let mut parsed = parse(&self.config, &mut self.stats, obis)
+            .instrument(tracing::debug_span!("parse"))
+            .await;
+        if parsed.is_empty() {
+            self.stats.invalid_telegrams += 1;
+            return false;
+        }
+        let gas = parsed.get("gasUsageAccumulative");
+        let mut measurements = parsed.take_measurements();
+        self.reject_outliers(&mut measurements);
+
+        // Some meters don't include a timestamp, the measurements are still posted then
+        let timestamp = parsed.timestamp;
+        match timestamp {
+            Some(_timestamp) => {
+                verbose!("Timestamp: {:?}", _timestamp);
+                // The meter clock is synchronised by the grid operator, a growing drift means
+                // either the meter or the system clock is off
+                let meter_clock_drift = _timestamp - system_timestamp();
+                verbose!("Meter clock drift: {:?}", meter_clock_drift);
+                measurements.push(("meterClockDrift", FieldValue::Int(meter_clock_drift)));
+            }
+            None => verbose!("No telegram timestamp, using the system time"),
+        }
+
+        if let Some(_gas_unit) = gas.as_ref().and_then(|_gas| _gas.unit.as_ref()) {
+            if *_gas_unit != GAS_UNIT && self.gas_unit.as_ref() != Some(_gas_unit) {
+                log!(
+                    "Warning: the gas meter reports in {} instead of {}, posting it as is",
+                    _gas_unit,
+                    GAS_UNIT
+                );
+            }
+            self.gas_unit = Some(_gas_unit.clone());
+        }
+        let gas_usage = gas.and_then(|_gas| match _gas.value {
+            FieldValue::Float(_gas_usage) => Some(_gas_usage),
+            _ => None,
+        });
+        if let (Some(_gas_timestamp), Some(_gas_usage)) = (parsed.gas_timestamp, gas_usage) {
+            // A gas meter that stopped reporting (e.g. an empty battery) leaves its last reading
+            // in the telegrams, which would look like no gas is used
+            let gas_age = timestamp.unwrap_or_else(system_timestamp) - _gas_timestamp;
+            let gas_stale = gas_age > gas_stale_after(&self.config, obis).await.as_secs() as i64;
+            if gas_stale && !self.gas_stale {
+                log!(
+                    "Warning: the gas reading is {} minutes old, the gas meter stopped reporting",
+                    gas_age / 60
+                );
+            } else if !gas_stale && self.gas_stale {
+                info!("The gas meter is reporting again");
+            }
+            self.gas_stale = gas_stale;
+            measurements.push(("gasStale", FieldValue::Int(gas_stale as i64)));
+
+            if gas_stale {
+                measurements.retain(|(key, _)| !GAS_MEASUREMENTS.contains(key));
+                self.gas_flow_rate = None;
+            } else if has_measurement(&measurements, "gasUsageAccumulative") {
+                self.update_gas_flow_rate(_gas_timestamp, _gas_usage);
+            }
+        }
+        if let Some(_gas_flow_rate) = self.gas_flow_rate {
+            verbose!("Gas flow rate: {:?}", _gas_flow_rate);
+            measurements.push(("gasFlowRate", FieldValue::Float(_gas_flow_rate)));
+        }
+
+        if let Ok((_, _entries)) = parse_power_failure_log(obis).await {
+            self.count_failure_events(&_entries);
+            verbose!("Failure events: {:?}", self.failure_events);
+            measurements.push(("failureEvents", FieldValue::Int(self.failure_events)));
+        }
+
+        if self.config.derive_power {
+            if let Some(_w_usage_accumulative) =
+                float_measurement(&measurements, "wattUsageAccumulative")
+            {
+                self.update_w_usage_derived(_w_usage_accumulative);
+            }
+            if let Some(_w_usage_derived) = self.w_usage_derived {
+                verbose!("Watt usage derived: {:?}", _w_usage_derived);
+                measurements.push(("wattUsageDerived", FieldValue::Float(_w_usage_derived)));
+            }
+        }
+        *self.last_telegram.lock().unwrap() = Some(Instant::now());
+
+        #[cfg(feature = "systemd")]
+        notify_systemd(sd_notify::NotifyState::Watchdog);
+
+        if let Some(_smooth_alpha) = self.config.smooth_alpha {
+            self.smooth(&mut measurements, _smooth_alpha);
+        }
+
+        // Prometheus scrapes on its own schedule, so the metrics always hold the latest telegram
+        *self.metrics.lock().unwrap() = metrics::render(&measurements, &self.stats);
+
+        parsed.set_measurements(measurements);
+        if let Some(mut _posted) = self.interval_buffer.push(parsed) {
+            let mut _measurements = _posted.take_measurements();
+            if self.config.post_deltas {
+                self.add_deltas(&mut _measurements);
+            }
+            if self.config.dedupe_accumulative {
+                self.dedupe_accumulative(&mut _measurements);
+            }
+            if self.config.post_on_change_only {
+                self.drop_unchanged(&mut _measurements);
+            }
+            let config = &self.config;
+            _measurements.retain(|(key, _)| is_measurement_selected(config, key));
+            // Tagged like the telegram they were parsed from
+            _posted.set_measurements(_measurements);
+
+            if self.config.json {
+                print_json(&_posted);
+            } else if self.config.stdout_only {
+                for line in line_protocol(&self.config, &_posted) {
+                    println!("{}", line);
+                }
+            } else {
+                // InfluxDB and Graphite run side by side, each gets every telegram
+                if !self.config.influx_urls.is_empty() {
+                    self.post_influx_db(&_posted).await;
+                }
+                if self.graphite.is_some() {
+                    self.send_graphite(&_posted).await;
+                }
+            }
+        }
+
+        if Instant::now() >= self.next_state_save {
+            self.save_state();
+            self.next_state_save = Instant::now() + STATE_SAVE_INTERVAL;
+        }
+        true
+    }
+
+    // Post the measurements of a telegram to every InfluxDB target, or collect them for the next
+    // flush with `--flush-interval`
+    async fn post_influx_db(&mut self, telegram: &Telegram) {
+        let lines = line_protocol(&self.config, telegram);
+        if self.config.flush_interval.is_some() {
+            self.pending_lines.extend(lines);
+            if Instant::now() >= self.next_flush
+                || self.pending_lines.len() >= self.config.flush_max_lines
+            {
+                self.scheduled_flush().await;
+            }
+            return;
+        }
+        self.post_lines(lines).await;
+    }
+
+    // Send the measurements of a telegram to the Carbon server of `--graphite`
+    async fn send_graphite(&mut self, telegram: &Telegram) {
+        let graphite = match self.graphite.as_mut() {
+            Some(_graphite) => _graphite,
+            None => return,
+        };
+        let lines = graphite::plaintext(&self.config.graphite_prefix, telegram, system_timestamp());
+        let span = tracing::debug_span!("post", url = self.config.graphite.as_deref());
+        if graphite.send(&lines).instrument(span).await {
+            self.stats.posts += lines.len() as u64;
+        } else {
+            self.stats.failed_posts += lines.len() as u64;
+        }
+    }
+
+    // When the lines collected with `--flush-interval` are due to be posted, if there are any
+    pub fn flush_due(&self) -> Option<Instant> {
+        match self.config.flush_interval {
+            Some(_) if !self.pending_lines.is_empty() => Some(self.next_flush),
+            _ => None,
+        }
+    }
+
+    // Post the collected lines and start the next `--flush-interval`
+    pub async fn scheduled_flush(&mut self) {
+        self.flush().await;
+        self.next_flush = Instant::now() + self.config.flush_interval.unwrap_or_default();
+    }
+
+    // Post what is still collected and write the state file, before the adapter stops
+    pub async fn finish(&mut self) {
+        self.flush().await;
+        self.save_state();
+    }
+
+    // Post the lines collected with `--flush-interval` to every target in a single request
+    async fn flush(&mut self) {
+        if self.pending_lines.is_empty() {
+            return;
+        }
+        let lines = std::mem::take(&mut self.pending_lines);
+        self.post_lines(lines).await;
+    }
+
+    // Post lines to every InfluxDB target in a single request each, in parallel so a slow or
+    // unreachable target doesn't hold up the others. A single request is retried as a whole, a
+    // request per line would take the retries of every line when InfluxDB is down.
+    async fn post_lines(&mut self, lines: Vec<String>) {
+        if lines.is_empty() {
+            return;
+        }
+        let body = lines.join("\n");
+        let posts = self.config.influx_urls.iter().map(|_influx_url| {
+            post_influx_db(
+                &self.client,
+                _influx_url,
+                body.clone(),
+                self.config.influx_gzip,
+                self.config.influx_retries,
+            )
+            .instrument(tracing::debug_span!("post", url = _influx_url.as_str()))
+        });
+        for accepted in future::join_all(posts).await {
+            if accepted {
+                self.stats.posts += lines.len() as u64;
+            } else {
+                self.stats.failed_posts += lines.len() as u64;
+            }
+        }
+    }
+
+    // Continue from the state of before a restart
+    pub fn restore_state(&mut self, state: State) {
+        let now = SystemTime::now();
+        for (name, time, value) in state.counters {
+            // The time since the counter was received, as an `Instant` of this run
+            let age = now.duration_since(time).unwrap_or_default();
+            let instant = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+            self.previous_counters.insert(name, (instant, value));
+        }
+        self.previous_gas = state.gas;
+        for (name, value) in state.last_posted {
+            self.last_posted.insert(name, FieldValue::Float(value));
+        }
+        self.delta_counters.extend(state.delta_counters);
+    }
+
+    fn save_state(&self) {
+        let path = match &self.config.state_file {
+            Some(_path) => _path,
+            None => return,
+        };
+        let now = SystemTime::now();
+        let state = State {
+            counters: self
+                .previous_counters
+                .iter()
+                .map(|(name, (instant, value))| (*name, now - instant.elapsed(), *value))
+                .collect(),
+            gas: self.previous_gas,
+            last_posted: self
+                .last_posted
+                .iter()
+                .filter_map(|(name, value)| match value {
+                    FieldValue::Float(_value) => Some((*name, *_value)),
+                    _ => None,
+                })
+                .collect(),
+            delta_counters: self
+                .delta_counters
+                .iter()
+                .map(|(name, value)| (*name, *value))
+                .collect(),
+        };
+        if let Err(_err) = state.save(path) {
+            log!("Warning: could not write state file {}: {}", path, _err);
+        }
+    }
+
+    // Drop implausible values, which a corrupted telegram can contain: power beyond `--max-watt`,
+    // accumulative counters that jumped further than `--max-watt` (electricity, over the time
+    // since the previous telegram) or `--max-gas-delta` (gas) allow and, unless
+    // `--allow-counter-reset`, counters that went down. Measurements calculated from a dropped
+    // value are dropped as well.
+    fn reject_outliers(&mut self, measurements: &mut Vec<(&'static str, FieldValue)>) {
+        let now = Instant::now();
+        let config = &self.config;
+        let previous_counters = &mut self.previous_counters;
+        let rejected_counters = &mut self.rejected_counters;
+        let mut rejected = Vec::new();
+        measurements.retain(|(key, value)| {
+            // Derived measurements come after the values they are calculated from
+            let derived_from_rejected = DERIVED_MEASUREMENTS.iter().any(|(_name, _inputs)| {
+                _name == key && _inputs.iter().any(|_input| rejected.contains(_input))
+            });
+            if derived_from_rejected {
+                log!("Warning: dropping {}, calculated from a dropped value", key);
+                return false;
+            }
+            let plausible = is_plausible(
+                config,
+                previous_counters,
+                rejected_counters,
+                now,
+                key,
+                value,
+            );
+            if !plausible {
+                rejected.push(*key);
+            }
+            plausible
+        });
+    }
+
+    // Replace the instantaneous measurements by their exponential moving average, `alpha` is the
+    // weight of the latest value
+    fn smooth(&mut self, measurements: &mut [(&'static str, FieldValue)], alpha: f64) {
+        for (key, value) in measurements.iter_mut() {
+            if let FieldValue::Float(_value) = value {
+                if INSTANTANEOUS_MEASUREMENTS.contains(key) {
+                    let smoothed = match self.smoothed.get(key) {
+                        Some(_previous) => alpha * *_value + (1.0 - alpha) * _previous,
+                        None => *_value,
+                    };
+                    self.smoothed.insert(key, smoothed);
+                    *value = FieldValue::Float(smoothed.round());
+                }
+            }
+        }
+    }
+
+    // Drop accumulative counters that didn't change since they were last posted
+    fn dedupe_accumulative(&mut self, measurements: &mut Vec<(&'static str, FieldValue)>) {
+        let last_posted = &mut self.last_posted;
+        measurements.retain(|(key, value)| {
+            if !ACCUMULATIVE_MEASUREMENTS.contains(key) {
+                return true;
+            }
+            if last_posted.get(key) == Some(value) {
+                return false;
+            }
+            last_posted.insert(key, value.clone());
+            true
+        });
+    }
+
+    // Drop the measurements that didn't change (more than the tolerance of their kind) since they
+    // were last posted, unless that was `--max-stale` ago
+    fn drop_unchanged(&mut self, measurements: &mut Vec<(&'static str, FieldValue)>) {
+        let now = Instant::now();
+        let config = &self.config;
+        let posted_on_change = &mut self.posted_on_change;
+        measurements.retain(|(key, value)| {
+            if LIVENESS_MEASUREMENTS.contains(key) {
+                return true;
+            }
+            let tolerance = if INSTANTANEOUS_MEASUREMENTS.contains(key) {
+                config.change_tolerance_power
+            } else if ACCUMULATIVE_MEASUREMENTS.contains(key) {
+                config.change_tolerance_counter
+            } else {
+                0.0
+            };
+            if let Some((_posted_at, _posted_value)) = posted_on_change.get(key) {
+                let unchanged = match (_posted_value, value) {
+                    (FieldValue::Float(_posted), FieldValue::Float(_value)) => {
+                        (_value - _posted).abs() <= tolerance
+                    }
+                    _ => _posted_value == value,
+                };
+                if unchanged && now.duration_since(*_posted_at) < config.max_stale {
+                    return false;
+                }
+            }
+            posted_on_change.insert(key, (now, value.clone()));
+            true
+        });
+    }
+
+    // Add the usage since the previous post of the accumulative counters. The first post after
+    // startup, and the first after a counter went down, has no previous value to compare with.
+    fn add_deltas(&mut self, measurements: &mut Vec<(&'static str, FieldValue)>) {
+        for (counter, delta) in DELTA_MEASUREMENTS {
+            let value = match float_measurement(measurements, counter) {
+                Some(_value) => _value,
+                None => continue,
+            };
+            match self.delta_counters.insert(counter, value) {
+                Some(_previous) if value >= _previous => {
+                    let _delta = round_kwh(value - _previous);
+                    verbose!("{}: {:?}", delta, _delta);
+                    measurements.push((delta, FieldValue::Float(_delta)));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Recalculate the gas flow rate when the gas meter reported a new reading. The gas meter
+    // is only read every 5 minutes (hourly on older meters), so most telegrams repeat the
+    // previous reading.
+    fn update_gas_flow_rate(&mut self, timestamp: i64, gas_usage: f64) {
+        match self.previous_gas {
+            Some((_previous_timestamp, _)) if _previous_timestamp == timestamp => return,
+            Some((_previous_timestamp, _previous_gas_usage)) if _previous_timestamp < timestamp => {
+                let hours = (timestamp - _previous_timestamp) as f64 / 3600.0;
+                self.gas_flow_rate = Some((gas_usage - _previous_gas_usage) / hours);
+            }
+            _ => {}
+        }
+        self.previous_gas = Some((timestamp, gas_usage));
+    }
+
+    // Count the failures that were added to the power failure event log since the previous
+    // telegram. The log only holds the last few failures, so unlike failureEventCount this only
+    // goes up. The failures logged before the first telegram aren't counted.
+    fn count_failure_events(&mut self, entries: &[(i64, i64)]) {
+        if let Some(_last_failure_end) = self.last_failure_end {
+            self.failure_events += entries
+                .iter()
+                .filter(|(_end_time, _)| *_end_time > _last_failure_end)
+                .count() as i64;
+        }
+        let last_failure_end = entries
+            .iter()
+            .map(|(_end_time, _)| *_end_time)
+            .max()
+            .unwrap_or(i64::MIN);
+        self.last_failure_end = Some(match self.last_failure_end {
+            Some(_previous) => _previous.max(last_failure_end),
+            None => last_failure_end,
+        });
+    }
+
+    // Recalculate the derived usage when the accumulative usage changed. The counter has a
+    // resolution of 1 Wh, so the usage is derived from the time between two changes rather
+    // than between two telegrams. The first change only starts the measurement, as the time
+    // the counter was at its initial value is unknown. While the counter doesn't change, less
+    // than 1 Wh was used since the last change, which bounds the usage so it decays towards 0.
+    fn update_w_usage_derived(&mut self, w_usage_accumulative: f64) {
+        let now = Instant::now();
+        match self.previous_usage_accumulative {
+            Some((_previous_time, _previous_value, _))
+                if _previous_value == w_usage_accumulative =>
+            {
+                let hours = now.duration_since(_previous_time).as_secs_f64() / 3600.0;
+                // 1 Wh -> W
+                let w_usage_bound = (1.0 / hours).round();
+                if let Some(_w_usage_derived) = self.w_usage_derived {
+                    self.w_usage_derived = Some(_w_usage_derived.min(w_usage_bound));
+                }
+                return;
+            }
+            Some((_previous_time, _previous_value, true)) => {
+                let hours = now.duration_since(_previous_time).as_secs_f64() / 3600.0;
+                // kWh -> W
+                let w_usage_derived = (w_usage_accumulative - _previous_value) * 1000.0 / hours;
+                self.w_usage_derived = Some(w_usage_derived.round());
+            }
+            _ => {}
+        }
+        let changed = self.previous_usage_accumulative.is_some();
+        self.previous_usage_accumulative = Some((now, w_usage_accumulative, changed));
+    }
+}
+
+// Print measurements as a single JSON object on stdout, stamped with the telegram timestamp or
+// the system time if the telegram has none
+fn print_json(telegram: &Telegram) {
+    let mut object = serde_json::Map::new();
+
+    let timestamp = telegram.timestamp.unwrap_or_else(system_timestamp);
+    object.insert("timestamp".to_string(), timestamp.into());
+
+    for measurement in telegram.measurements() {
+        let value = match measurement.value {
+            FieldValue::Float(_value) => serde_json::Number::from_f64(_value)
+                .map(serde_json::Value::Number)
+                .unwrap_or(serde_json::Value::Null),
+            FieldValue::Int(_value) => _value.into(),
+            FieldValue::Str(_value) => _value.into(),
+        };
+        object.insert(measurement.name.to_string(), value);
+    }
+
+    println!("{}", serde_json::Value::Object(object));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telegram::Measurement;
+    use futures::executor::block_on;
+
+    // Parse a telegram
+    fn parse_with(config: &Config, telegram: &str) -> Vec<(&'static str, FieldValue)> {
+        let obis = ObisValues::parse(telegram);
+        block_on(parse(config, &mut Stats::default(), &obis)).take_measurements()
+    }
+
+    // Adapter that isn't connected to anything
+    fn adapter(config: Config) -> Adapter {
+        Adapter::new(
+            &config,
+            reqwest::Client::new(),
+            Default::default(),
+            Default::default(),
+        )
+    }
+
+    fn fixture(name: &str) -> String {
+        std::fs::read_to_string(format!("fixtures/{}", name)).unwrap()
+    }
+
+    fn measurement(measurements: &[(&'static str, FieldValue)], name: &str) -> Option<FieldValue> {
+        measurements
+            .iter()
+            .find(|(_name, _)| *_name == name)
+            .map(|(_, _value)| _value.clone())
+    }
+
+    #[test]
+    fn drops_decreased_counters_and_what_is_calculated_from_them() {
+        let config = Config {
+            price_tariff_1: Some(0.2),
+            price_tariff_2: Some(0.25),
+            combined_power: true,
+            ..Config::default()
+        };
+        let mut adapter = adapter(config.clone());
+        let mut measurements = parse_with(&config, &fixture("dsmr42.txt"));
+        adapter.reject_outliers(&mut measurements);
+        assert!(measurement(&measurements, "costElectricity").is_some());
+
+        // A corrupted read of the tariff 1 counter
+        let telegram = fixture("dsmr42.txt").replace("(007392.132*kWh)", "(000392.132*kWh)");
+        let mut measurements = parse_with(&config, &telegram);
+        adapter.reject_outliers(&mut measurements);
+        for _name in &[
+            "wattUsageAccumulativeTariff1",
+            "wattUsageAccumulative",
+            "wattAccumulativeNett",
+            "powerFlowAccumulative",
+            "costElectricity",
+        ] {
+            assert_eq!(measurement(&measurements, _name), None, "{}", _name);
+        }
+        assert_eq!(
+            measurement(&measurements, "wattUsageAccumulativeTariff2"),
+            Some(FieldValue::Float(7139.8))
+        );
+        assert_eq!(
+            measurement(&measurements, "wattUsage"),
+            Some(FieldValue::Float(131.0))
+        );
+
+        // Unless the meter was replaced
+        let mut adapter = self::adapter(Config {
+            allow_counter_reset: true,
+            ..config.clone()
+        });
+        let mut measurements = parse_with(&config, &fixture("dsmr42.txt"));
+        adapter.reject_outliers(&mut measurements);
+        let mut measurements = parse_with(&config, &telegram);
+        adapter.reject_outliers(&mut measurements);
+        assert_eq!(
+            measurement(&measurements, "wattUsageAccumulativeTariff1"),
+            Some(FieldValue::Float(392.132))
+        );
+        assert!(measurement(&measurements, "costElectricity").is_some());
+    }
+
+    #[test]
+    fn drops_implausible_power_and_counter_jumps() {
+        let config = Config {
+            max_watt: Some(10000.0),
+            max_gas_delta: Some(1.0),
+            ..Config::default()
+        };
+        let mut adapter = adapter(config.clone());
+        let mut measurements = parse_with(&config, &fixture("dsmr42.txt"));
+        adapter.reject_outliers(&mut measurements);
+        assert_eq!(
+            measurement(&measurements, "gasUsageAccumulative"),
+            Some(FieldValue::Float(7025.512))
+        );
+
+        // Megawatts of usage, and a counter and gas reading that jumped within a second
+        let telegram = fixture("dsmr42.txt")
+            .replace("(00.131*kW)", "(99.131*kW)")
+            .replace("(007139.800*kWh)", "(017139.800*kWh)")
+            .replace("(07025.512*m3)", "(07125.512*m3)");
+        let mut measurements = parse_with(&config, &telegram);
+        adapter.reject_outliers(&mut measurements);
+        for _name in &[
+            "wattUsage",
+            "wattNett",
+            "gridPower",
+            "wattUsageAccumulativeTariff2",
+            "wattUsageAccumulative",
+            "gasUsageAccumulative",
+        ] {
+            assert_eq!(measurement(&measurements, _name), None, "{}", _name);
+        }
+        assert_eq!(
+            measurement(&measurements, "wattUsageAccumulativeTariff1"),
+            Some(FieldValue::Float(7392.132))
+        );
+
+        // A jump that is still there in the next telegram is real
+        let mut measurements = parse_with(&config, &telegram);
+        adapter.reject_outliers(&mut measurements);
+        assert_eq!(
+            measurement(&measurements, "wattUsageAccumulativeTariff2"),
+            Some(FieldValue::Float(17139.8))
+        );
+        assert_eq!(
+            measurement(&measurements, "gasUsageAccumulative"),
+            Some(FieldValue::Float(7125.512))
+        );
+        assert_eq!(measurement(&measurements, "wattUsage"), None);
+    }
+
+    #[test]
+    fn keeps_the_nett_counter_going_down() {
+        let config = Config::default();
+        let mut adapter = adapter(config.clone());
+        let mut measurements = parse_with(&config, &fixture("dsmr42.txt"));
+        adapter.reject_outliers(&mut measurements);
+
+        // With the default `--nett-sign` the nett counter goes down when energy is used
+        let telegram = fixture("dsmr42.txt").replace("(007392.132*kWh)", "(007392.200*kWh)");
+        let mut measurements = parse_with(&config, &telegram);
+        adapter.reject_outliers(&mut measurements);
+        assert_eq!(
+            measurement(&measurements, "wattAccumulativeNett"),
+            Some(FieldValue::Float(-8290.499))
+        );
+    }
+
+    #[test]
+    fn posts_only_changed_measurements_and_the_heartbeat() {
+        let config = Config {
+            post_on_change_only: true,
+            change_tolerance_power: 10.0,
+            ..Config::default()
+        };
+        let mut adapter = adapter(config.clone());
+        let mut measurements = parse_with(&config, &fixture("dsmr42.txt"));
+        let parsed = measurements.len();
+        adapter.drop_unchanged(&mut measurements);
+        assert_eq!(measurements.len(), parsed);
+
+        // Within the tolerance
+        let telegram = fixture("dsmr42.txt").replace("(00.131*kW)", "(00.141*kW)");
+        let mut measurements = parse_with(&config, &telegram);
+        adapter.drop_unchanged(&mut measurements);
+        assert_eq!(measurements, vec![("adapterHeartbeat", FieldValue::Int(1))]);
+
+        let telegram = fixture("dsmr42.txt").replace("(00.131*kW)", "(00.142*kW)");
+        let mut measurements = parse_with(&config, &telegram);
+        adapter.drop_unchanged(&mut measurements);
+        assert_eq!(
+            measurement(&measurements, "wattUsage"),
+            Some(FieldValue::Float(142.0))
+        );
+        assert_eq!(
+            measurement(&measurements, "adapterHeartbeat"),
+            Some(FieldValue::Int(1))
+        );
+        assert_eq!(measurement(&measurements, "wattUsageAccumulative"), None);
+
+        // An unchanged measurement is posted again after `--max-stale`
+        let mut adapter = self::adapter(Config {
+            max_stale: Duration::from_secs(0),
+            ..config.clone()
+        });
+        let mut measurements = parse_with(&config, &fixture("dsmr42.txt"));
+        adapter.drop_unchanged(&mut measurements);
+        let mut measurements = parse_with(&config, &fixture("dsmr42.txt"));
+        adapter.drop_unchanged(&mut measurements);
+        assert_eq!(measurements.len(), parsed);
+    }
+
+    // Measurements of a telegram with the given power and accumulative usage
+    fn power_and_counter(
+        w_usage: f64,
+        w_usage_accumulative: f64,
+    ) -> Vec<(&'static str, FieldValue)> {
+        vec![
+            ("wattUsage", FieldValue::Float(w_usage)),
+            (
+                "wattUsageAccumulative",
+                FieldValue::Float(w_usage_accumulative),
+            ),
+        ]
+    }
+
+    // Push the measurements of a telegram into the buffer, returns the measurements it posts
+    fn push(
+        buffer: &mut IntervalBuffer,
+        measurements: Vec<(&'static str, FieldValue)>,
+    ) -> Option<Vec<(&'static str, FieldValue)>> {
+        buffer
+            .push(Telegram::new(measurements, None))
+            .map(|mut _telegram| _telegram.take_measurements())
+    }
+
+    #[test]
+    fn interval_buffer_posts_the_latest_telegram_once_per_interval() {
+        let interval = Duration::from_secs(10);
+        let mut buffer = IntervalBuffer::new(Some(interval), Aggregate::Last);
+        // The first telegram is posted right away
+        assert_eq!(
+            push(&mut buffer, power_and_counter(100.0, 1.0)),
+            Some(power_and_counter(100.0, 1.0))
+        );
+        assert_eq!(push(&mut buffer, power_and_counter(200.0, 1.1)), None);
+        assert_eq!(push(&mut buffer, power_and_counter(300.0, 1.2)), None);
+
+        buffer.last_post = Instant::now().checked_sub(interval);
+        assert_eq!(
+            push(&mut buffer, power_and_counter(400.0, 1.3)),
+            Some(power_and_counter(400.0, 1.3))
+        );
+        assert_eq!(push(&mut buffer, power_and_counter(500.0, 1.4)), None);
+
+        // Without an interval every telegram is posted
+        let mut buffer = IntervalBuffer::new(None, Aggregate::Last);
+        for _ in 0..3 {
+            assert_eq!(
+                push(&mut buffer, power_and_counter(100.0, 1.0)),
+                Some(power_and_counter(100.0, 1.0))
+            );
+        }
+    }
+
+    #[test]
+    fn interval_buffer_posts_the_mean_power_and_the_last_counter() {
+        let interval = Duration::from_secs(10);
+        let mut buffer = IntervalBuffer::new(Some(interval), Aggregate::Mean);
+        assert_eq!(
+            push(&mut buffer, power_and_counter(100.0, 1.0)),
+            Some(power_and_counter(100.0, 1.0))
+        );
+        assert_eq!(push(&mut buffer, power_and_counter(200.0, 1.1)), None);
+        assert_eq!(push(&mut buffer, power_and_counter(600.0, 1.2)), None);
+
+        // The samples of the previous interval don't count towards the mean
+        buffer.last_post = Instant::now().checked_sub(interval);
+        assert_eq!(
+            push(&mut buffer, power_and_counter(100.0, 1.3)),
+            Some(power_and_counter(300.0, 1.3))
+        );
+    }
+
+    #[test]
+    fn interval_buffer_posts_the_mean_of_measurements_missing_from_the_latest_telegram() {
+        let interval = Duration::from_secs(10);
+        let mut buffer = IntervalBuffer::new(Some(interval), Aggregate::Mean);
+        buffer.last_post = Some(Instant::now());
+        let telegram = |_measurements, _timestamp| Telegram::new(_measurements, Some(_timestamp));
+        assert!(buffer
+            .push(telegram(power_and_counter(100.0, 1.0), 1613119480))
+            .is_none());
+        assert!(buffer
+            .push(telegram(power_and_counter(300.0, 1.1), 1613119481))
+            .is_none());
+
+        // The power of the latest telegram was dropped, e.g. as implausible
+        buffer.last_post = Instant::now().checked_sub(interval);
+        let latest = vec![("wattUsageAccumulative", FieldValue::Float(1.2))];
+        let posted = buffer.push(telegram(latest, 1613119482)).unwrap();
+        assert_eq!(
+            posted.get("wattUsage").unwrap(),
+            Measurement {
+                name: "wattUsage",
+                value: FieldValue::Float(200.0),
+                timestamp: Some(1613119481),
+                unit: None,
+                device_type: None,
+            }
+        );
+        assert_eq!(
+            posted.get("wattUsageAccumulative").unwrap().timestamp,
+            Some(1613119482)
+        );
+    }
+
+    #[test]
+    fn dedupes_unchanged_accumulative_counters_only() {
+        let mut adapter = adapter(Config::default());
+        let mut measurements = power_and_counter(100.0, 1.0);
+        adapter.dedupe_accumulative(&mut measurements);
+        assert_eq!(measurements, power_and_counter(100.0, 1.0));
+
+        // Power is posted also when it didn't change
+        let mut measurements = power_and_counter(100.0, 1.0);
+        adapter.dedupe_accumulative(&mut measurements);
+        assert_eq!(measurements, [("wattUsage", FieldValue::Float(100.0))]);
+
+        let mut measurements = power_and_counter(100.0, 1.001);
+        adapter.dedupe_accumulative(&mut measurements);
+        assert_eq!(measurements, power_and_counter(100.0, 1.001));
+    }
+
+    #[test]
+    fn smooths_power_but_not_the_counters() {
+        let mut adapter = adapter(Config::default());
+        let mut measurements = power_and_counter(100.0, 1.0);
+        adapter.smooth(&mut measurements, 0.25);
+        assert_eq!(measurements, power_and_counter(100.0, 1.0));
+
+        let mut measurements = power_and_counter(500.0, 1.1);
+        adapter.smooth(&mut measurements, 0.25);
+        assert_eq!(measurements, power_and_counter(200.0, 1.1));
+
+        let mut measurements = power_and_counter(200.0, 1.2);
+        adapter.smooth(&mut measurements, 0.25);
+        assert_eq!(measurements, power_and_counter(200.0, 1.2));
+    }
+
+    #[test]
+    fn adds_the_usage_since_the_previous_post() {
+        let mut adapter = adapter(Config::default());
+        let telegram = |_energy: f64, _gas: f64| {
+            vec![
+                ("wattUsageAccumulative", FieldValue::Float(_energy)),
+                ("gasUsageAccumulative", FieldValue::Float(_gas)),
+            ]
+        };
+        // Nothing to compare the first post with
+        let mut measurements = telegram(7392.132, 7025.512);
+        adapter.add_deltas(&mut measurements);
+        assert_eq!(measurements, telegram(7392.132, 7025.512));
+
+        let mut measurements = telegram(7392.632, 7025.512);
+        adapter.add_deltas(&mut measurements);
+        assert_eq!(
+            measurement(&measurements, "energyUsedDelta"),
+            Some(FieldValue::Float(0.5))
+        );
+        assert_eq!(
+            measurement(&measurements, "gasUsedDelta"),
+            Some(FieldValue::Float(0.0))
+        );
+
+        // A counter that went down starts over
+        let mut measurements = telegram(7392.132, 7025.612);
+        adapter.add_deltas(&mut measurements);
+        assert_eq!(measurement(&measurements, "energyUsedDelta"), None);
+        assert_eq!(
+            measurement(&measurements, "gasUsedDelta"),
+            Some(FieldValue::Float(0.1))
+        );
+        let mut measurements = telegram(7392.232, 7025.612);
+        adapter.add_deltas(&mut measurements);
+        assert_eq!(
+            measurement(&measurements, "energyUsedDelta"),
+            Some(FieldValue::Float(0.1))
+        );
+    }
+
+    #[test]
+    fn calculates_the_gas_flow_rate_between_two_readings() {
+        let mut adapter = adapter(Config::default());
+
+        // Without a previous reading there is no rate yet
+        adapter.update_gas_flow_rate(1612526400, 7025.0);
+        assert_eq!(adapter.gas_flow_rate, None);
+
+        // 0.25 m3 in 5 minutes
+        adapter.update_gas_flow_rate(1612526700, 7025.25);
+        assert_eq!(adapter.gas_flow_rate, Some(3.0));
+    }
+
+    #[test]
+    fn keeps_the_gas_flow_rate_until_a_newer_reading() {
+        let mut adapter = adapter(Config::default());
+        adapter.update_gas_flow_rate(1612526400, 7025.0);
+        adapter.update_gas_flow_rate(1612526700, 7025.25);
+
+        // Every telegram repeats the reading until the gas meter is read again
+        adapter.update_gas_flow_rate(1612526700, 7025.25);
+        assert_eq!(adapter.gas_flow_rate, Some(3.0));
+        assert_eq!(adapter.previous_gas, Some((1612526700, 7025.25)));
+
+        // An older reading, e.g. when the meter sends the readings out of order
+        adapter.update_gas_flow_rate(1612526100, 7024.5);
+        assert_eq!(adapter.gas_flow_rate, Some(3.0));
+    }
+
+    #[test]
+    fn counts_the_failures_added_to_the_event_log() {
+        let mut adapter = adapter(Config::default());
+        // A placeholder entry next to a failure logged before the adapter started
+        adapter.count_failure_events(&[(1544095652, 7692), (946684801, 2147483647)]);
+        assert_eq!(adapter.failure_events, 0);
+
+        adapter.count_failure_events(&[(1544095652, 7692), (1612526400, 60)]);
+        assert_eq!(adapter.failure_events, 1);
+
+        // The oldest failure dropped out of the log, the count of the log stays the same
+        adapter.count_failure_events(&[(1612526400, 60), (1612530000, 5)]);
+        assert_eq!(adapter.failure_events, 2);
+        adapter.count_failure_events(&[(1612526400, 60), (1612530000, 5)]);
+        assert_eq!(adapter.failure_events, 2);
+    }
+
+    #[test]
+    fn derives_power_from_the_counter_and_decays_it_while_unchanged() {
+        let mut adapter = adapter(Config::default());
+        let ago = |_secs: u64| {
+            Instant::now()
+                .checked_sub(Duration::from_secs(_secs))
+                .unwrap()
+        };
+
+        // The time the counter was at its initial value is unknown, the first change only starts
+        // the measurement
+        adapter.update_w_usage_derived(1.0);
+        adapter.previous_usage_accumulative = Some((ago(60), 1.0, false));
+        adapter.update_w_usage_derived(1.01);
+        assert_eq!(adapter.w_usage_derived, None);
+
+        // 10 Wh in 36 seconds
+        adapter.previous_usage_accumulative = Some((ago(36), 1.01, true));
+        adapter.update_w_usage_derived(1.02);
+        assert_eq!(adapter.w_usage_derived, Some(1000.0));
+
+        // Less than 1 Wh was used in the 36 seconds the counter didn't change
+        adapter.previous_usage_accumulative = Some((ago(36), 1.02, true));
+        adapter.update_w_usage_derived(1.02);
+        assert_eq!(adapter.w_usage_derived, Some(100.0));
+    }
+
+    #[test]
+    fn stops_posting_gas_when_the_gas_reading_is_stale() {
+        // The gas reading of the fluvius telegram is 8 minutes old, a DSMR 5 gas meter is read
+        // every 5 minutes
+        let telegram = fixture("fluvius.txt");
+        let obis = ObisValues::parse(&telegram);
+        let config = Config {
+            stdout_only: true,
+            ..Config::default()
+        };
+        assert_eq!(
+            block_on(gas_stale_after(&config, &obis)),
+            Duration::from_secs(10 * 60)
+        );
+        let mut fresh = adapter(config.clone());
+        assert!(block_on(fresh.handle_telegram(&telegram)));
+        let metrics = fresh.metrics.lock().unwrap().clone();
+        assert!(metrics.contains("\ndsmr_gas_stale 0\n"), "{}", metrics);
+        assert!(metrics.contains("\ndsmr_gas_usage_accumulative_total 112.384\n"));
+
+        let config = Config {
+            gas_stale_after: Some(Duration::from_secs(5 * 60)),
+            ..config
+        };
+        let mut stale = adapter(config);
+        assert!(block_on(stale.handle_telegram(&telegram)));
+        let metrics = stale.metrics.lock().unwrap().clone();
+        assert!(metrics.contains("\ndsmr_gas_stale 1\n"), "{}", metrics);
+        assert!(!metrics.contains("dsmr_gas_usage_accumulative"));
+        assert!(!metrics.contains("dsmr_cost_gas"));
+    }
+
+    #[test]
+    fn health_check_fails_without_a_recent_telegram() {
+        let max_age = Duration::from_secs(30);
+        let mut adapter = adapter(Config {
+            stdout_only: true,
+            ..Config::default()
+        });
+        let status = |_adapter: &Adapter| health::health_status(&_adapter.last_telegram, max_age);
+        assert_eq!(status(&adapter), "503 Service Unavailable");
+
+        // Nothing could be parsed from a garbled telegram
+        assert!(!block_on(adapter.handle_telegram("/garbled\r\n\r\n!\r\n")));
+        assert_eq!(status(&adapter), "503 Service Unavailable");
+
+        assert!(block_on(adapter.handle_telegram(&fixture("dsmr42.txt"))));
+        assert_eq!(status(&adapter), "200 OK");
+
+        *adapter.last_telegram.lock().unwrap() = Instant::now().checked_sub(2 * max_age);
+        assert_eq!(status(&adapter), "503 Service Unavailable");
+    }
+}
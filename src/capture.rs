@@ -0,0 +1,51 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+
+// Appends raw telegrams to a file, e.g. to attach to a bug report or to replay later
+pub struct Capture {
+    path: String,
+    file: File,
+    // Number of telegrams still to capture, unlimited if `None`
+    remaining: Option<u64>,
+}
+
+impl Capture {
+    pub fn open(path: &str, count: Option<u64>) -> io::Result<Capture> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Capture {
+            path: path.to_string(),
+            file,
+            remaining: count,
+        })
+    }
+
+    // Append a complete telegram, separated from the previous one by a blank line
+    pub fn write(&mut self, telegram: &str) {
+        if self.remaining == Some(0) {
+            return;
+        }
+
+        // Telegrams normally end with a line break after the CRC, but make sure of it
+        let separator = if telegram.ends_with('\n') {
+            "\r\n"
+        } else {
+            "\r\n\r\n"
+        };
+        let result = self
+            .file
+            .write_all(telegram.as_bytes())
+            .and_then(|_| self.file.write_all(separator.as_bytes()))
+            .and_then(|_| self.file.flush());
+        if let Err(_err) = result {
+            log!("Could not write telegram to {}: {}", self.path, _err);
+            return;
+        }
+
+        if let Some(_remaining) = self.remaining.as_mut() {
+            *_remaining -= 1;
+            if *_remaining == 0 {
+                info!("Capture to {} complete", self.path);
+            }
+        }
+    }
+}
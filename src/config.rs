@@ -0,0 +1,990 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_TTY: &str = "/dev/ttyUSB0";
+const INFLUX_DB_URI: &str = "http://localhost:8086/write?db=p1meter";
+// Host tag used when the hostname of the machine can not be determined
+const UNKNOWN_HOST: &str = "unknown";
+
+// Options that can be repeated on the command line, e.g. `--influx-url` to post to multiple
+// targets. Every occurrence is a value of its own, so a value can contain a comma. As an
+// environment variable the values are comma separated, in a config file they are an array.
+const REPEATABLE: &[&str] = &["influx-url", "extra-field", "tag"];
+
+// Options that don't take a value on the command line, in a config file they take a boolean
+const FLAGS: &[&str] = &[
+    "once",
+    "assert-rts",
+    "json",
+    "obis-dump",
+    "derive-power",
+    "dedupe-accumulative",
+    "post-deltas",
+    "post-on-change-only",
+    "stdout-only",
+    "list-ports",
+    "simulate",
+    "stdin",
+    "tariff-tag",
+    "no-nett",
+    "combined-power",
+    "verbose",
+    "quiet",
+    "no-startup-check",
+    "influx-gzip",
+    "influx-insecure",
+    "allow-counter-reset",
+];
+
+// Options that take a value
+const OPTIONS: &[&str] = &[
+    "config",
+    "tty",
+    "tcp",
+    "influx-url",
+    "influx-scheme",
+    "influx-host",
+    "influx-port",
+    "influx-db",
+    "influx-timeout",
+    "influx-retries",
+    "influx-precision",
+    "influx-ca-cert",
+    "extra-field",
+    "tag",
+    "graphite",
+    "graphite-prefix",
+    "pool-size",
+    "interval",
+    "flush-interval",
+    "flush-max-lines",
+    "aggregate",
+    "nett-sign",
+    "smooth-alpha",
+    "price-tariff1",
+    "price-tariff2",
+    "price-gas",
+    "max-watt",
+    "max-gas-delta",
+    "gas-stale-after",
+    "change-tolerance-power",
+    "change-tolerance-counter",
+    "max-stale",
+    "health-port",
+    "health-max-age",
+    "timeout",
+    "dsmr-version",
+    "meter-profile",
+    "baud",
+    "data-bits",
+    "parity",
+    "stop-bits",
+    "request-interval",
+    "read-timeout",
+    "stall-timeout",
+    "startup-timeout",
+    "max-telegram-size",
+    "measurements",
+    "exclude-measurements",
+    "capture",
+    "capture-count",
+    "state-file",
+    "stats-every",
+    "rate-every",
+    "host",
+    "measurement-prefix",
+    "single-measurement",
+];
+
+// How instantaneous measurements are combined over a posting interval
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregate {
+    // Post the value of the latest telegram
+    Last,
+    // Post the mean of all telegrams received within the interval
+    Mean,
+}
+
+// Which OBIS ids the telegrams of the meter have
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MeterProfile {
+    // Dutch and Belgian DSMR meters
+    Dsmr,
+    // Swedish and Norwegian meters with a HAN port
+    Nordic,
+}
+
+// Unit of the timestamps posted to InfluxDB
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Precision {
+    Seconds,
+    Milliseconds,
+    Microseconds,
+    Nanoseconds,
+}
+
+impl Precision {
+    // Value of the `precision` parameter of the InfluxDB write endpoint
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Precision::Seconds => "s",
+            Precision::Milliseconds => "ms",
+            Precision::Microseconds => "us",
+            Precision::Nanoseconds => "ns",
+        }
+    }
+
+    // Convert a Unix timestamp in seconds to this unit
+    pub fn convert(self, timestamp: i64) -> i64 {
+        match self {
+            Precision::Seconds => timestamp,
+            Precision::Milliseconds => timestamp * 1_000,
+            Precision::Microseconds => timestamp * 1_000_000,
+            Precision::Nanoseconds => timestamp * 1_000_000_000,
+        }
+    }
+}
+
+// Direction in which nett power is calculated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NettSign {
+    // Positive when producing more than using (feeding back to the grid)
+    ProductionMinusUsage,
+    // Positive when using more than producing (drawing from the grid)
+    UsageMinusProduction,
+}
+
+impl NettSign {
+    pub fn apply(self, production: f64, usage: f64) -> f64 {
+        match self {
+            NettSign::ProductionMinusUsage => production - usage,
+            NettSign::UsageMinusProduction => usage - production,
+        }
+    }
+}
+
+impl fmt::Display for NettSign {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NettSign::ProductionMinusUsage => write!(f, "production-minus-usage"),
+            NettSign::UsageMinusProduction => write!(f, "usage-minus-production"),
+        }
+    }
+}
+
+// DSMR protocol version, determines the default serial port settings
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DsmrVersion {
+    // DSMR 2.2 and 3.0 meters: 9600 baud, 7 data bits, even parity, 1 stop bit
+    V2,
+    // DSMR 4.x and 5.0 meters: 115200 baud, 8 data bits, no parity, 1 stop bit
+    V5,
+}
+
+// Options that can be set in the config file, as environment variables or on the command line
+#[derive(Clone)]
+pub struct Config {
+    // Serial port the meter is connected to
+    pub tty: String,
+    // Network P1 reader (`host:port`) to read telegrams from instead of the serial port
+    pub tcp: Option<String>,
+    // InfluxDB write endpoints including the database name, measurements are posted to each
+    pub influx_urls: Vec<String>,
+    // Whether the endpoints were given with `influx-url` in any of the config sources, rather than
+    // being the default
+    pub influx_urls_given: bool,
+    // Components the InfluxDB write endpoint is assembled from when `--influx-url` isn't given
+    pub influx_scheme: Option<String>,
+    pub influx_host: Option<String>,
+    pub influx_port: Option<u16>,
+    pub influx_db: Option<String>,
+    // Maximum time a post to InfluxDB may take
+    pub influx_timeout: Duration,
+    // Times a post that timed out or got a 5xx response is retried
+    pub influx_retries: u32,
+    // Unit of the timestamps posted to InfluxDB
+    pub influx_precision: Precision,
+    // PEM file with a root certificate to trust, e.g. of a self-signed InfluxDB behind HTTPS
+    pub influx_ca_cert: Option<String>,
+    // Constant fields as (key, value) added to every line posted to InfluxDB
+    pub extra_fields: Vec<(String, String)>,
+    // Tags as (key, value) added to every line posted to InfluxDB, next to `host`
+    pub tags: Vec<(String, String)>,
+    // Carbon server (`host:port`) to send the measurements to, next to or instead of InfluxDB
+    pub graphite: Option<String>,
+    // Prepended to the measurement names to form the Graphite metric paths
+    pub graphite_prefix: String,
+    // Maximum number of idle connections kept open to each InfluxDB host
+    pub pool_size: usize,
+    // Minimum time between two posts, telegrams received in between are discarded
+    pub interval: Option<Duration>,
+    // Collect the lines of the telegrams and post them to InfluxDB in one request this often
+    pub flush_interval: Option<Duration>,
+    // Number of collected lines that are posted right away, regardless of `--flush-interval`
+    pub flush_max_lines: usize,
+    pub aggregate: Aggregate,
+    pub nett_sign: NettSign,
+    // Weight of the latest value in the exponential moving average of instantaneous measurements
+    pub smooth_alpha: Option<f64>,
+    // Prices per kWh of tariff 1 and 2 and per m3 of gas, used to calculate costs
+    pub price_tariff_1: Option<f64>,
+    pub price_tariff_2: Option<f64>,
+    pub price_gas: Option<f64>,
+    // Power in W beyond which a value is considered corrupt, also bounds the accumulative
+    // electricity counters
+    pub max_watt: Option<f64>,
+    // Change in m3 of the gas reading between two telegrams beyond which it is considered corrupt
+    pub max_gas_delta: Option<f64>,
+    // Age of the gas reading after which the gas meter is considered to have stopped reporting
+    pub gas_stale_after: Option<Duration>,
+    // Port to serve the `/health` liveness endpoint on
+    pub health_port: Option<u16>,
+    // Maximum time since the last parsed telegram for `/health` to report healthy
+    pub health_max_age: Duration,
+    // Exit after posting the first valid telegram
+    pub once: bool,
+    // Maximum time to wait for a valid telegram in `--once` mode
+    pub timeout: Duration,
+    pub dsmr_version: DsmrVersion,
+    // OBIS ids to read from the telegrams
+    pub meter_profile: MeterProfile,
+    // Serial port settings overriding the defaults of the DSMR version
+    pub baud_rate: Option<u32>,
+    pub data_bits: Option<mio_serial::DataBits>,
+    pub parity: Option<mio_serial::Parity>,
+    pub stop_bits: Option<mio_serial::StopBits>,
+    // Assert the RTS and DTR lines (the P1 data request line) after opening the port
+    pub assert_rts: bool,
+    // Periodically pulse the data request line, for meters that send one telegram per request
+    pub request_interval: Option<Duration>,
+    // Timeout of a single read from the serial port
+    pub read_timeout: Duration,
+    // Warn when no data was received from the meter for this long
+    pub stall_timeout: Duration,
+    // Log troubleshooting hints when no valid telegram was parsed this long after startup
+    pub startup_timeout: Duration,
+    // Discard telegrams that grow beyond this many bytes
+    pub max_telegram_size: usize,
+    // Print measurements as JSON lines on stdout instead of posting them to InfluxDB
+    pub json: bool,
+    // Print the line protocol on stdout instead of posting it to InfluxDB
+    pub stdout_only: bool,
+    // Print every OBIS id of the telegrams and its values instead of posting measurements
+    pub obis_dump: bool,
+    // Print the available serial ports and exit
+    pub list_ports: bool,
+    // Print the usage and exit, only on the command line
+    pub help: bool,
+    // Process generated telegrams instead of reading a meter
+    pub simulate: bool,
+    // Read telegrams from stdin instead of the serial port
+    pub stdin: bool,
+    // Tag every line posted to InfluxDB with the current tariff
+    pub tariff_tag: bool,
+    // Don't post wattNett and wattAccumulativeNett
+    pub no_nett: bool,
+    // Post powerFlow and powerFlowAccumulative, usage minus production
+    pub combined_power: bool,
+    // Log every telegram, the values parsed from it and the posts to InfluxDB
+    pub verbose: bool,
+    // Only log warnings and errors
+    pub quiet: bool,
+    // Only warn instead of exiting when InfluxDB is unreachable at startup
+    pub no_startup_check: bool,
+    // Compress the bodies posted to InfluxDB with gzip
+    pub influx_gzip: bool,
+    // Don't verify the certificate of an HTTPS InfluxDB
+    pub influx_insecure: bool,
+    // Accept accumulative counters that went down, e.g. after the meter was replaced
+    pub allow_counter_reset: bool,
+    // Derive the usage from the accumulative usage as a cross-check of the reported usage
+    pub derive_power: bool,
+    // Only post accumulative counters when they changed since they were last posted
+    pub dedupe_accumulative: bool,
+    // Post the usage since the previous post next to the accumulative counters
+    pub post_deltas: bool,
+    // Only post measurements that changed since they were last posted
+    pub post_on_change_only: bool,
+    // Change of the instantaneous power in W and of the accumulative counters in kWh or m3 that
+    // `--post-on-change-only` doesn't count as a change
+    pub change_tolerance_power: f64,
+    pub change_tolerance_counter: f64,
+    // Time after which `--post-on-change-only` posts a measurement again even when unchanged
+    pub max_stale: Duration,
+    // Only post these measurements, all measurements are posted if not set
+    pub measurements: Option<Vec<String>>,
+    // Never post these measurements
+    pub exclude_measurements: Vec<String>,
+    // Append every complete raw telegram to this file
+    pub capture: Option<String>,
+    // Stop capturing after this many telegrams
+    pub capture_count: Option<u64>,
+    // Keep the previous values the derived measurements depend on in this file across restarts
+    pub state_file: Option<String>,
+    // Log the parse and post statistics every this many telegrams
+    pub stats_every: Option<u64>,
+    // Log the rate of bytes and telegrams read from the meter every this long
+    pub rate_every: Option<Duration>,
+    // Value of the `host` tag of everything posted to InfluxDB
+    pub host: String,
+    // Prepended to the name of every measurement posted to InfluxDB
+    pub measurement_prefix: String,
+    // Post all measurements as fields of a single InfluxDB measurement with this name
+    pub single_measurement: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            tty: DEFAULT_TTY.to_string(),
+            tcp: None,
+            influx_urls: vec![INFLUX_DB_URI.to_string()],
+            influx_urls_given: false,
+            influx_scheme: None,
+            influx_host: None,
+            influx_port: None,
+            influx_db: None,
+            influx_timeout: Duration::from_secs(5),
+            influx_retries: 2,
+            influx_precision: Precision::Seconds,
+            influx_ca_cert: None,
+            extra_fields: Vec::new(),
+            tags: Vec::new(),
+            graphite: None,
+            graphite_prefix: String::new(),
+            pool_size: 1,
+            interval: None,
+            flush_interval: None,
+            flush_max_lines: 10000,
+            aggregate: Aggregate::Last,
+            nett_sign: NettSign::ProductionMinusUsage,
+            smooth_alpha: None,
+            price_tariff_1: None,
+            price_tariff_2: None,
+            price_gas: None,
+            max_watt: None,
+            max_gas_delta: None,
+            gas_stale_after: None,
+            health_port: None,
+            health_max_age: Duration::from_secs(30),
+            once: false,
+            timeout: Duration::from_secs(30),
+            dsmr_version: DsmrVersion::V5,
+            meter_profile: MeterProfile::Dsmr,
+            baud_rate: None,
+            data_bits: None,
+            parity: None,
+            stop_bits: None,
+            assert_rts: false,
+            request_interval: None,
+            read_timeout: Duration::from_millis(1),
+            stall_timeout: Duration::from_secs(15),
+            startup_timeout: Duration::from_secs(20),
+            max_telegram_size: 8192,
+            json: false,
+            stdout_only: false,
+            obis_dump: false,
+            list_ports: false,
+            help: false,
+            simulate: false,
+            stdin: false,
+            tariff_tag: false,
+            no_nett: false,
+            combined_power: false,
+            verbose: false,
+            quiet: false,
+            no_startup_check: false,
+            influx_gzip: false,
+            influx_insecure: false,
+            allow_counter_reset: false,
+            derive_power: false,
+            dedupe_accumulative: false,
+            post_deltas: false,
+            post_on_change_only: false,
+            change_tolerance_power: 0.0,
+            change_tolerance_counter: 0.0,
+            max_stale: Duration::from_secs(300),
+            measurements: None,
+            exclude_measurements: Vec::new(),
+            capture: None,
+            capture_count: None,
+            state_file: None,
+            stats_every: None,
+            rate_every: None,
+            host: hostname::get()
+                .ok()
+                .and_then(|_host| _host.into_string().ok())
+                .unwrap_or_else(|| UNKNOWN_HOST.to_string()),
+            measurement_prefix: String::new(),
+            single_measurement: None,
+        }
+    }
+}
+
+impl Config {
+    // Load the config, each source overriding the previous one:
+    // defaults, config file (`--config` or `DSMR_CONFIG`), `DSMR_*` environment variables,
+    // command line arguments
+    pub fn load() -> Result<Config, String> {
+        Config::load_from(env::args().skip(1))
+    }
+
+    // Load the config from the given command line arguments instead of those of the process
+    fn load_from<I: Iterator<Item = String>>(args: I) -> Result<Config, String> {
+        let args = parse_args(args)?;
+        let mut config = Config::default();
+        if args.iter().any(|(name, _)| name == "help") {
+            config.help = true;
+            return Ok(config);
+        }
+
+        let config_file = args
+            .iter()
+            .rev()
+            .find(|(name, _)| name == "config")
+            .map(|(_, value)| value.clone())
+            .or_else(|| env::var("DSMR_CONFIG").ok());
+        if let Some(_config_file) = config_file {
+            config.apply_file(&_config_file)?;
+        }
+
+        for name in FLAGS.iter().chain(OPTIONS) {
+            let variable = format!("DSMR_{}", name.to_uppercase().replace('-', "_"));
+            if let Ok(_value) = env::var(&variable) {
+                config.set(name, &_value, &variable)?;
+            }
+        }
+
+        for (name, value) in &args {
+            if !REPEATABLE.contains(&name.as_str()) {
+                config.set(name, value, &format!("--{}", name))?;
+            }
+        }
+        for name in REPEATABLE {
+            let values: Vec<String> = args
+                .iter()
+                .filter(|(_name, _)| _name == name)
+                .map(|(_, value)| value.clone())
+                .collect();
+            if !values.is_empty() {
+                config.set_list(name, &values, &format!("--{}", name))?;
+            }
+        }
+
+        // The endpoint is either given as a whole or assembled from its components, not both
+        if config.influx_scheme.is_some()
+            || config.influx_host.is_some()
+            || config.influx_port.is_some()
+            || config.influx_db.is_some()
+        {
+            if config.influx_urls_given {
+                return Err(
+                    "--influx-url can not be combined with --influx-scheme, --influx-host, --influx-port or --influx-db"
+                        .to_string(),
+                );
+            }
+            // The certificate options only make sense for HTTPS, so they imply it
+            let tls = config.influx_ca_cert.is_some() || config.influx_insecure;
+            let scheme = match &config.influx_scheme {
+                Some(_scheme) => _scheme.as_str(),
+                None if tls => "https",
+                None => "http",
+            };
+            config.influx_urls = vec![format!(
+                "{}://{}:{}/write?db={}",
+                scheme,
+                config.influx_host.as_deref().unwrap_or("localhost"),
+                config.influx_port.unwrap_or(8086),
+                config.influx_db.as_deref().unwrap_or("p1meter")
+            )];
+        } else if config.graphite.is_some() && !config.influx_urls_given {
+            // Only post to InfluxDB next to Graphite when it's configured explicitly, not to the
+            // default endpoint
+            config.influx_urls = Vec::new();
+        }
+        Ok(config)
+    }
+
+    // Apply all options from a TOML config file, using the option names as keys
+    fn apply_file(&mut self, path: &str) -> Result<(), String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|_err| format!("Could not read config file {}: {}", path, _err))?;
+        let table = match contents.parse::<toml::Value>() {
+            Ok(toml::Value::Table(_table)) => _table,
+            Ok(_) => return Err(format!("Invalid config file {}", path)),
+            Err(_err) => return Err(format!("Invalid config file {}: {}", path, _err)),
+        };
+
+        for (name, value) in &table {
+            let source = format!("{} in {}", name, path);
+            // Config files can't include each other
+            if name == "config" {
+                return Err(format!("Unknown option: {}", source));
+            }
+            let value = match value {
+                toml::Value::String(_value) => _value.clone(),
+                toml::Value::Integer(_value) => _value.to_string(),
+                toml::Value::Float(_value) => _value.to_string(),
+                toml::Value::Boolean(_value) => _value.to_string(),
+                // Lists are written as an array of strings instead of comma separated
+                toml::Value::Array(_values) => {
+                    let values: Option<Vec<String>> = _values
+                        .iter()
+                        .map(|_value| _value.as_str().map(str::to_string))
+                        .collect();
+                    match values {
+                        Some(_values) if REPEATABLE.contains(&name.as_str()) => {
+                            self.set_list(name, &_values, &source)?;
+                            continue;
+                        }
+                        Some(_values) => _values.join(","),
+                        None => return Err(format!("Invalid value for {}", source)),
+                    }
+                }
+                _ => return Err(format!("Invalid value for {}", source)),
+            };
+            self.set(name, &value, &source)?;
+        }
+        Ok(())
+    }
+
+    // Set a single option, `source` describes where the value came from for error messages
+    fn set(&mut self, name: &str, value: &str, source: &str) -> Result<(), String> {
+        let invalid = || format!("Invalid value for {}: {}", source, value);
+
+        match name {
+            // Handled before any other option is applied
+            "config" => {}
+            "tty" => self.tty = value.to_string(),
+            "tcp" => self.tcp = Some(value.to_string()),
+            "influx-url" | "extra-field" | "tag" => {
+                self.set_list(name, &parse_list(value), source)?
+            }
+            "influx-scheme" => match value {
+                "http" | "https" => self.influx_scheme = Some(value.to_string()),
+                _ => return Err(format!("Invalid value for {}: {}", source, value)),
+            },
+            "influx-host" => self.influx_host = Some(value.to_string()),
+            "influx-port" => self.influx_port = Some(parse_value(value, source)?),
+            "influx-db" => self.influx_db = Some(value.to_string()),
+            "influx-ca-cert" => self.influx_ca_cert = Some(value.to_string()),
+            "graphite" => self.graphite = Some(value.to_string()),
+            "graphite-prefix" => self.graphite_prefix = value.to_string(),
+            "influx-timeout" => self.influx_timeout = parse_seconds(value, source)?,
+            "influx-retries" => self.influx_retries = parse_value(value, source)?,
+            "influx-precision" => {
+                self.influx_precision = match value {
+                    "s" => Precision::Seconds,
+                    "ms" => Precision::Milliseconds,
+                    "us" => Precision::Microseconds,
+                    "ns" => Precision::Nanoseconds,
+                    _ => return Err(invalid()),
+                };
+            }
+            "pool-size" => self.pool_size = parse_value(value, source)?,
+            "interval" => self.interval = Some(parse_seconds(value, source)?),
+            "flush-interval" => self.flush_interval = Some(parse_seconds(value, source)?),
+            "flush-max-lines" => {
+                self.flush_max_lines = match parse_value(value, source)? {
+                    0 => return Err(invalid()),
+                    _lines => _lines,
+                }
+            }
+            "aggregate" => {
+                self.aggregate = match value {
+                    "last" => Aggregate::Last,
+                    "mean" => Aggregate::Mean,
+                    _ => return Err(invalid()),
+                };
+            }
+            "nett-sign" => {
+                self.nett_sign = match value {
+                    "production-minus-usage" => NettSign::ProductionMinusUsage,
+                    "usage-minus-production" => NettSign::UsageMinusProduction,
+                    _ => return Err(invalid()),
+                };
+            }
+            "smooth-alpha" => {
+                let smooth_alpha: f64 = parse_value(value, source)?;
+                if !(smooth_alpha > 0.0 && smooth_alpha <= 1.0) {
+                    return Err(invalid());
+                }
+                self.smooth_alpha = Some(smooth_alpha);
+            }
+            "price-tariff1" => self.price_tariff_1 = Some(parse_value(value, source)?),
+            "price-tariff2" => self.price_tariff_2 = Some(parse_value(value, source)?),
+            "price-gas" => self.price_gas = Some(parse_value(value, source)?),
+            "max-watt" => self.max_watt = Some(parse_value(value, source)?),
+            "max-gas-delta" => self.max_gas_delta = Some(parse_value(value, source)?),
+            "change-tolerance-power" => self.change_tolerance_power = parse_value(value, source)?,
+            "change-tolerance-counter" => {
+                self.change_tolerance_counter = parse_value(value, source)?
+            }
+            "max-stale" => self.max_stale = parse_seconds(value, source)?,
+            "gas-stale-after" => self.gas_stale_after = Some(parse_seconds(value, source)?),
+            "health-port" => self.health_port = Some(parse_value(value, source)?),
+            "health-max-age" => self.health_max_age = parse_seconds(value, source)?,
+            "once" => self.once = parse_bool(value, source)?,
+            "timeout" => self.timeout = parse_seconds(value, source)?,
+            "dsmr-version" => {
+                self.dsmr_version = match value {
+                    "2.2" | "3.0" => DsmrVersion::V2,
+                    "4.0" | "4.2" | "5.0" => DsmrVersion::V5,
+                    _ => return Err(invalid()),
+                };
+            }
+            "meter-profile" => {
+                self.meter_profile = match value {
+                    "dsmr" => MeterProfile::Dsmr,
+                    "nordic" => MeterProfile::Nordic,
+                    _ => return Err(invalid()),
+                };
+            }
+            "baud" => self.baud_rate = Some(parse_value(value, source)?),
+            "data-bits" => {
+                self.data_bits = match value {
+                    "5" => Some(mio_serial::DataBits::Five),
+                    "6" => Some(mio_serial::DataBits::Six),
+                    "7" => Some(mio_serial::DataBits::Seven),
+                    "8" => Some(mio_serial::DataBits::Eight),
+                    _ => return Err(invalid()),
+                };
+            }
+            "parity" => {
+                self.parity = match value {
+                    "none" => Some(mio_serial::Parity::None),
+                    "odd" => Some(mio_serial::Parity::Odd),
+                    "even" => Some(mio_serial::Parity::Even),
+                    _ => return Err(invalid()),
+                };
+            }
+            "stop-bits" => {
+                self.stop_bits = match value {
+                    "1" => Some(mio_serial::StopBits::One),
+                    "2" => Some(mio_serial::StopBits::Two),
+                    _ => return Err(invalid()),
+                };
+            }
+            "assert-rts" => self.assert_rts = parse_bool(value, source)?,
+            "request-interval" => self.request_interval = Some(parse_seconds(value, source)?),
+            "read-timeout" => {
+                self.read_timeout = Duration::from_millis(parse_value(value, source)?)
+            }
+            "stall-timeout" => self.stall_timeout = parse_seconds(value, source)?,
+            "startup-timeout" => self.startup_timeout = parse_seconds(value, source)?,
+            "max-telegram-size" => self.max_telegram_size = parse_value(value, source)?,
+            "json" => self.json = parse_bool(value, source)?,
+            "stdout-only" => self.stdout_only = parse_bool(value, source)?,
+            "obis-dump" => self.obis_dump = parse_bool(value, source)?,
+            "list-ports" => self.list_ports = parse_bool(value, source)?,
+            "simulate" => self.simulate = parse_bool(value, source)?,
+            "stdin" => self.stdin = parse_bool(value, source)?,
+            "tariff-tag" => self.tariff_tag = parse_bool(value, source)?,
+            "no-nett" => self.no_nett = parse_bool(value, source)?,
+            "combined-power" => self.combined_power = parse_bool(value, source)?,
+            "verbose" => self.verbose = parse_bool(value, source)?,
+            "quiet" => self.quiet = parse_bool(value, source)?,
+            "no-startup-check" => self.no_startup_check = parse_bool(value, source)?,
+            "influx-gzip" => self.influx_gzip = parse_bool(value, source)?,
+            "influx-insecure" => self.influx_insecure = parse_bool(value, source)?,
+            "allow-counter-reset" => self.allow_counter_reset = parse_bool(value, source)?,
+            "derive-power" => self.derive_power = parse_bool(value, source)?,
+            "dedupe-accumulative" => self.dedupe_accumulative = parse_bool(value, source)?,
+            "post-deltas" => self.post_deltas = parse_bool(value, source)?,
+            "post-on-change-only" => self.post_on_change_only = parse_bool(value, source)?,
+            "measurements" => self.measurements = Some(parse_list(value)),
+            "exclude-measurements" => self.exclude_measurements = parse_list(value),
+            "capture" => self.capture = Some(value.to_string()),
+            "capture-count" => self.capture_count = Some(parse_value(value, source)?),
+            "state-file" => self.state_file = Some(value.to_string()),
+            "stats-every" => {
+                self.stats_every = match parse_value(value, source)? {
+                    0 => return Err(invalid()),
+                    _stats_every => Some(_stats_every),
+                };
+            }
+            "rate-every" => {
+                self.rate_every = match parse_seconds(value, source)? {
+                    _rate_every if _rate_every == Duration::from_secs(0) => return Err(invalid()),
+                    _rate_every => Some(_rate_every),
+                };
+            }
+            "host" => self.host = value.to_string(),
+            "measurement-prefix" => self.measurement_prefix = value.to_string(),
+            "single-measurement" => self.single_measurement = Some(value.to_string()),
+            _ => return Err(format!("Unknown option: {}", source)),
+        }
+        Ok(())
+    }
+
+    // Set a repeatable option from its values, e.g. one `--tag` per value
+    fn set_list(&mut self, name: &str, values: &[String], source: &str) -> Result<(), String> {
+        let invalid = |value: &str| format!("Invalid value for {}: {}", source, value);
+        match name {
+            "influx-url" => {
+                if values.is_empty() || values.iter().any(|_url| _url.is_empty()) {
+                    return Err(invalid(&values.join(",")));
+                }
+                self.influx_urls = values.to_vec();
+                self.influx_urls_given = true;
+            }
+            "extra-field" => {
+                self.extra_fields = Vec::new();
+                for field in values {
+                    match field.split_once('=') {
+                        Some((_key, _value)) if !_key.is_empty() => self
+                            .extra_fields
+                            .push((_key.to_string(), _value.to_string())),
+                        _ => return Err(invalid(field)),
+                    }
+                }
+            }
+            "tag" => {
+                self.tags = Vec::new();
+                for tag in values {
+                    match tag.split_once('=') {
+                        Some((_key, _value)) if !_key.is_empty() && !_value.is_empty() => {
+                            self.tags.push((_key.to_string(), _value.to_string()))
+                        }
+                        _ => return Err(invalid(tag)),
+                    }
+                }
+            }
+            _ => return Err(format!("Unknown option: {}", source)),
+        }
+        Ok(())
+    }
+
+    // Serial port settings for the configured DSMR version, with explicit overrides applied
+    pub fn serial_settings(&self) -> mio_serial::SerialPortSettings {
+        let (baud_rate, data_bits, parity) = match self.dsmr_version {
+            DsmrVersion::V2 => (9600, mio_serial::DataBits::Seven, mio_serial::Parity::Even),
+            DsmrVersion::V5 => (
+                115200,
+                mio_serial::DataBits::Eight,
+                mio_serial::Parity::None,
+            ),
+        };
+
+        mio_serial::SerialPortSettings {
+            baud_rate: self.baud_rate.unwrap_or(baud_rate),
+            data_bits: self.data_bits.unwrap_or(data_bits),
+            flow_control: mio_serial::FlowControl::None,
+            parity: self.parity.unwrap_or(parity),
+            stop_bits: self.stop_bits.unwrap_or(mio_serial::StopBits::One),
+            timeout: self.read_timeout,
+        }
+    }
+}
+
+// Usage printed by `--help`, generated from the known options
+pub fn usage() -> String {
+    let mut usage = format!("Usage: {} [options]\n\nFlags:\n", env!("CARGO_PKG_NAME"));
+    for name in FLAGS {
+        usage.push_str(&format!("  --{}\n", name));
+    }
+    usage.push_str("\nOptions:\n");
+    for name in OPTIONS {
+        usage.push_str(&format!("  --{} <value>\n", name));
+    }
+    usage.push_str(
+        "\nOptions can also be set as DSMR_* environment variables or in a TOML config file, \
+         see the README for what each option does\n",
+    );
+    usage
+}
+
+// Split command line arguments into (option name, value) pairs, flags get the value "true"
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Vec<(String, String)>, String> {
+    let mut parsed = Vec::new();
+    while let Some(arg) = args.next() {
+        let name = match arg.strip_prefix("--") {
+            Some(_name) => _name,
+            None if arg == "-h" => "help",
+            None => return Err(format!("Unknown argument: {}", arg)),
+        };
+
+        if FLAGS.contains(&name) || name == "help" {
+            parsed.push((name.to_string(), "true".to_string()));
+        } else if OPTIONS.contains(&name) {
+            let value = args.next().ok_or(format!("Missing value for {}", arg))?;
+            parsed.push((name.to_string(), value));
+        } else {
+            return Err(format!("Unknown argument: {}", arg));
+        }
+    }
+    Ok(parsed)
+}
+
+// Flags take `true`/`false`, `1`/`0`, `yes`/`no` or `on`/`off` as a value, as is common for
+// environment variables
+fn parse_bool(value: &str, source: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" => Ok(false),
+        _ => Err(format!("Invalid value for {}: {}", source, value)),
+    }
+}
+
+fn parse_value<T: FromStr>(value: &str, source: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", source, value))
+}
+
+// Split a comma separated list, ignoring surrounding whitespace and empty entries
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|_item| _item.trim())
+        .filter(|_item| !_item.is_empty())
+        .map(|_item| _item.to_string())
+        .collect()
+}
+
+fn parse_seconds(value: &str, source: &str) -> Result<Duration, String> {
+    Ok(Duration::from_secs(parse_value(value, source)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(args: &[&str]) -> Result<Config, String> {
+        Config::load_from(args.iter().map(|_arg| _arg.to_string()))
+    }
+
+    #[test]
+    fn repeated_options_keep_commas_in_their_values() {
+        let config = load(&[
+            "--tag",
+            "region=eu,west",
+            "--tag",
+            "location=garage",
+            "--extra-field",
+            "loc=garage, left",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.tags,
+            [
+                ("region".to_string(), "eu,west".to_string()),
+                ("location".to_string(), "garage".to_string())
+            ]
+        );
+        assert_eq!(
+            config.extra_fields,
+            [("loc".to_string(), "garage, left".to_string())]
+        );
+    }
+
+    #[test]
+    fn repeated_influx_urls_are_all_posted_to() {
+        let config = load(&[
+            "--influx-url",
+            "http://a:8086/write?db=p1meter",
+            "--influx-url",
+            "http://b:8086/write?db=p1meter",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.influx_urls,
+            [
+                "http://a:8086/write?db=p1meter",
+                "http://b:8086/write?db=p1meter"
+            ]
+        );
+    }
+
+    #[test]
+    fn environment_values_are_comma_separated() {
+        let mut config = Config::default();
+        config
+            .set("tag", "region=eu, location=garage", "DSMR_TAG")
+            .unwrap();
+        assert_eq!(
+            config.tags,
+            [
+                ("region".to_string(), "eu".to_string()),
+                ("location".to_string(), "garage".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn config_file_arrays_keep_commas_in_their_values() {
+        let path = env::temp_dir().join(format!("dsmr-config-test-{}.toml", std::process::id()));
+        fs::write(&path, "tag = [\"region=eu,west\"]\n").unwrap();
+        let mut config = Config::default();
+        let applied = config.apply_file(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        applied.unwrap();
+        assert_eq!(config.tags, [("region".to_string(), "eu,west".to_string())]);
+    }
+
+    #[test]
+    fn tags_need_a_key_and_a_value() {
+        assert!(load(&["--tag", "region"]).is_err());
+        assert!(load(&["--tag", "=eu"]).is_err());
+        assert!(load(&["--tag", "region="]).is_err());
+    }
+
+    #[test]
+    fn influx_endpoint_components_take_a_scheme() {
+        let config = load(&["--influx-host", "nas"]).unwrap();
+        assert_eq!(config.influx_urls, ["http://nas:8086/write?db=p1meter"]);
+
+        let config = load(&["--influx-scheme", "https", "--influx-host", "nas"]).unwrap();
+        assert_eq!(config.influx_urls, ["https://nas:8086/write?db=p1meter"]);
+
+        // The certificate options are only used for HTTPS
+        let config = load(&["--influx-host", "nas", "--influx-insecure"]).unwrap();
+        assert_eq!(config.influx_urls, ["https://nas:8086/write?db=p1meter"]);
+
+        assert!(load(&["--influx-scheme", "ftp"]).is_err());
+        // Also when the given URL happens to be the default one
+        assert!(load(&["--influx-url", INFLUX_DB_URI, "--influx-db", "energy"]).is_err());
+    }
+
+    #[test]
+    fn flags_take_the_common_boolean_values() {
+        let mut config = Config::default();
+        for _value in &["true", "1", "yes", "on", "ON"] {
+            config.set("once", _value, "DSMR_ONCE").unwrap();
+            assert!(config.once, "{}", _value);
+            config.set("once", "false", "DSMR_ONCE").unwrap();
+        }
+        for _value in &["false", "0", "no", "off", "Off"] {
+            config.set("once", "true", "DSMR_ONCE").unwrap();
+            config.set("once", _value, "DSMR_ONCE").unwrap();
+            assert!(!config.once, "{}", _value);
+        }
+        assert_eq!(
+            config.set("once", "2", "DSMR_ONCE"),
+            Err("Invalid value for DSMR_ONCE: 2".to_string())
+        );
+    }
+
+    #[test]
+    fn help_lists_every_option() {
+        assert!(load(&["--help"]).unwrap().help);
+        // Also next to options that would fail to load
+        assert!(load(&["-h", "--tag", "region"]).unwrap().help);
+        assert!(!load(&[]).unwrap().help);
+
+        let usage = usage();
+        for _name in FLAGS {
+            assert!(usage.contains(&format!("  --{}\n", _name)), "{}", _name);
+        }
+        for _name in OPTIONS {
+            assert!(
+                usage.contains(&format!("  --{} <value>\n", _name)),
+                "{}",
+                _name
+            );
+        }
+    }
+}
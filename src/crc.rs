@@ -0,0 +1,103 @@
+use crate::config::MeterProfile;
+
+// CRC16 of a telegram as used by DSMR 4 and 5, from the "/" up to and including the "!"
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+// Check the CRC after the end of frame char of a framed telegram. DSMR 2.2 and 3.0 telegrams end
+// with a bare "!", there is nothing to check then. Those are the only telegrams without a version
+// line, the HAN telegrams of the Nordic profile don't have one but always have a CRC.
+pub fn check(telegram: &str, profile: MeterProfile) -> Result<(), &'static str> {
+    // A "!" in a text message is always followed by the end of frame char, so the last one is it
+    let end = match telegram.rfind('!') {
+        Some(_end) => _end,
+        None => return Err("Missing end of frame"),
+    };
+    let start = telegram.find('/').unwrap_or(0);
+    let crc = telegram[end + 1..].trim_end();
+    if crc.is_empty() {
+        return match profile {
+            MeterProfile::Dsmr if !has_version(telegram) => Ok(()),
+            _ => Err("Missing CRC"),
+        };
+    }
+    let crc = match u16::from_str_radix(crc, 16) {
+        Ok(_crc) if crc.len() == 4 => _crc,
+        _ => return Err("Invalid CRC"),
+    };
+    let frame = &telegram[start..=end];
+    if crc16(frame.as_bytes()) == crc {
+        return Ok(());
+    }
+    // The meter calculated the CRC over `\r\n` line endings, bridges that strip the `\r` (e.g.
+    // ser2net or ESPHome) change the bytes but not the telegram
+    if !frame.contains('\r') && crc16(frame.replace('\n', "\r\n").as_bytes()) == crc {
+        return Ok(());
+    }
+    Err("CRC mismatch")
+}
+
+// Whether a telegram has the version line of DSMR 4 and later, or of Belgian meters
+fn has_version(telegram: &str) -> bool {
+    telegram
+        .lines()
+        .any(|_line| _line.starts_with("1-3:0.2.8(") || _line.starts_with("0-0:96.1.4("))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        std::fs::read_to_string(format!("fixtures/{}", name)).unwrap()
+    }
+
+    #[test]
+    fn checks_the_crc_of_telegrams() {
+        let dsmr = MeterProfile::Dsmr;
+        for (_fixture, _profile) in &[
+            ("dsmr42.txt", dsmr),
+            ("dsmr4-gas-slot.txt", dsmr),
+            ("fluvius.txt", dsmr),
+            ("han-sweden.txt", MeterProfile::Nordic),
+        ] {
+            let telegram = std::fs::read_to_string(format!("fixtures/{}", _fixture)).unwrap();
+            assert_eq!(check(&telegram, *_profile), Ok(()), "{}", _fixture);
+            // The CRC line was lost
+            let end = telegram.rfind('!').unwrap();
+            assert_eq!(
+                check(&telegram[..=end], *_profile),
+                Err("Missing CRC"),
+                "{}",
+                _fixture
+            );
+        }
+        // DSMR 3.0 telegrams don't have a CRC
+        let telegram = fixture("dsmr3.txt");
+        assert_eq!(check(&telegram, dsmr), Ok(()));
+
+        let telegram = fixture("dsmr42.txt");
+        // A bridge stripped the `\r`, the CRC is that of the original telegram
+        assert_eq!(check(&telegram.replace("\r\n", "\n"), dsmr), Ok(()));
+        let garbled = telegram.replace("(00.131*kW)", "(00.181*kW)");
+        assert_eq!(check(&garbled, dsmr), Err("CRC mismatch"));
+        assert_eq!(
+            check(&garbled.replace("\r\n", "\n"), dsmr),
+            Err("CRC mismatch")
+        );
+        let garbled = telegram.replace("!FC10", "!FC1G");
+        assert_eq!(check(&garbled, dsmr), Err("Invalid CRC"));
+    }
+}
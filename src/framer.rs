@@ -0,0 +1,109 @@
+use std::time::Instant;
+
+// Accumulates raw bytes read from the meter into complete telegrams
+pub struct Framer {
+    buffer: Vec<u8>,
+    // Telegrams growing beyond this many bytes are discarded, e.g. when the end of frame char is
+    // never seen because of a wrong baud rate
+    max_size: usize,
+    // Whether a telegram start of frame char was seen and the buffer holds its bytes
+    in_frame: bool,
+    // Whether the end of frame char was seen, the telegram completes at the end of its line
+    // (after the CRC)
+    in_trailer: bool,
+    // Whether the next byte is the first of a line. Lines only end at the `\n`, so telegrams of
+    // bridges (e.g. ser2net) that strip the `\r` are framed the same way. The bytes are kept as
+    // received, the CRC check restores the `\r` of such telegrams itself.
+    at_line_start: bool,
+    // When the start of frame char of the current telegram was received
+    frame_started: Instant,
+}
+
+impl Framer {
+    pub fn new(max_size: usize) -> Framer {
+        Framer {
+            buffer: Vec::new(),
+            max_size,
+            in_frame: false,
+            in_trailer: false,
+            at_line_start: true,
+            frame_started: Instant::now(),
+        }
+    }
+
+    // Add a chunk of bytes, returns the telegrams this chunk completed. A single read can hold the
+    // end of one telegram and the start (or all) of the next when the meter sends them back to
+    // back, the bytes after the last complete telegram are kept for the next chunk.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        let mut telegrams = Vec::new();
+        for &byte in chunk {
+            // DSMR telegrams are pure ASCII, anything else means the telegram is garbled. Only
+            // that telegram is lost, the chunk can hold more. Like a NUL the byte doesn't end a
+            // line, so a telegram right after it is still found.
+            if !byte.is_ascii() {
+                if self.in_frame {
+                    log!("Warning: received non-ASCII bytes, discarding telegram");
+                }
+                self.reset();
+                continue;
+            }
+
+            // The CRC after the end of frame char can arrive in a later chunk than the "!", so
+            // the telegram only completes at the line break after it. Should a meter leave out
+            // that line break, the next telegram completes it instead.
+            if byte == b'/' && self.in_trailer {
+                telegrams.extend(self.take_telegram());
+                self.at_line_start = true;
+            }
+
+            // The telegram start of frame char "/" and end of frame char "!" only count at the
+            // start of a line, they can also be part of a value (e.g. a text message)
+            if byte == b'/' && self.at_line_start {
+                self.reset();
+                self.in_frame = true;
+                self.frame_started = Instant::now();
+            }
+
+            if self.in_frame && self.buffer.len() >= self.max_size {
+                log!(
+                    "Warning: telegram exceeds {} bytes, discarding telegram",
+                    self.max_size
+                );
+                self.reset();
+            }
+
+            // Drop everything received while not inside a telegram
+            if self.in_frame {
+                self.buffer.push(byte);
+                if byte == b'!' && self.at_line_start {
+                    self.in_trailer = true;
+                }
+                if byte == b'\n' && self.in_trailer {
+                    telegrams.extend(self.take_telegram());
+                }
+            }
+
+            // Bytes before the start of frame (e.g. a leading NUL) don't start a new line
+            self.at_line_start = byte == b'\n' || (byte == 0 && self.at_line_start);
+        }
+        telegrams
+    }
+
+    fn take_telegram(&mut self) -> Option<String> {
+        let buffer = std::mem::take(&mut self.buffer);
+        self.reset();
+        tracing::debug!(
+            bytes = buffer.len(),
+            elapsed = ?self.frame_started.elapsed(),
+            "framed telegram"
+        );
+        // The buffer only ever contains ASCII, so this can not fail
+        String::from_utf8(buffer).ok()
+    }
+
+    fn reset(&mut self) {
+        self.buffer.clear();
+        self.in_frame = false;
+        self.in_trailer = false;
+    }
+}
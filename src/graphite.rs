@@ -0,0 +1,87 @@
+use crate::telegram::Telegram;
+use crate::FieldValue;
+use std::io;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+// Give up on a Carbon server that doesn't accept the connection or the data within this time
+const GRAPHITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Connection to a Carbon server, which is opened at the first send and reopened after an error
+pub struct Graphite {
+    address: String,
+    stream: Option<TcpStream>,
+}
+
+impl Graphite {
+    pub fn new(address: &str) -> Graphite {
+        Graphite {
+            address: address.to_string(),
+            stream: None,
+        }
+    }
+
+    // Send plaintext lines to the Carbon server, returns whether they were sent
+    pub async fn send(&mut self, lines: &[String]) -> bool {
+        if lines.is_empty() {
+            return true;
+        }
+        match tokio::time::timeout(GRAPHITE_TIMEOUT, self.write(lines.concat())).await {
+            Ok(Ok(())) => true,
+            Ok(Err(_err)) => {
+                log!("Graphite {}: Error {}", self.address, _err);
+                self.stream = None;
+                false
+            }
+            Err(_) => {
+                log!("Graphite {}: Error timed out", self.address);
+                self.stream = None;
+                false
+            }
+        }
+    }
+
+    async fn write(&mut self, data: String) -> io::Result<()> {
+        if self.stream.is_none() {
+            self.stream = Some(TcpStream::connect(&self.address).await?);
+            info!("Connected to Graphite at {}", self.address);
+        }
+        match self.stream.as_mut() {
+            Some(_stream) => _stream.write_all(data.as_bytes()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+// Format the measurements of a telegram in the Carbon plaintext protocol, a
+// `<path> <value> <timestamp>` line per measurement, e.g. `home.energy.wattUsage 131 1613119483`.
+// Graphite only stores numbers, text values are left out.
+pub fn plaintext(prefix: &str, telegram: &Telegram, now: i64) -> Vec<String> {
+    telegram
+        .measurements()
+        .filter_map(|_measurement| {
+            let value = match _measurement.value {
+                FieldValue::Float(_value) => _value.to_string(),
+                FieldValue::Int(_value) => _value.to_string(),
+                FieldValue::Str(_) => return None,
+            };
+            Some(format!(
+                "{} {} {}\n",
+                metric_path(prefix, _measurement.name),
+                value,
+                _measurement.timestamp.unwrap_or(now)
+            ))
+        })
+        .collect()
+}
+
+// Path of a measurement, e.g. `wattUsage` with the prefix `home.energy` is `home.energy.wattUsage`
+fn metric_path(prefix: &str, name: &str) -> String {
+    let prefix = prefix.trim_end_matches('.');
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", prefix, name)
+    }
+}
@@ -0,0 +1,84 @@
+use crate::metrics::Metrics;
+use std::io;
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// Time of the last successfully parsed telegram, shared between the read loop and the server
+pub type LastTelegram = Arc<Mutex<Option<Instant>>>;
+
+// Serve `GET /health`, responding 200 if a telegram was parsed within `max_age` and 503 otherwise,
+// and `GET /metrics` with the latest measurements as Prometheus metrics
+pub async fn serve(port: u16, max_age: Duration, last_telegram: LastTelegram, metrics: Metrics) {
+    let address = SocketAddr::from(([0, 0, 0, 0], port));
+    let mut listener = match TcpListener::bind(address).await {
+        Ok(_listener) => _listener,
+        Err(_err) => {
+            log!("Health check: could not listen on {}: {}", address, _err);
+            return;
+        }
+    };
+    info!("Health check: listening on http://{}/health", address);
+
+    loop {
+        match listener.accept().await {
+            Ok((_stream, _)) => {
+                let last_telegram = last_telegram.clone();
+                let metrics = metrics.clone();
+                tokio::spawn(async move {
+                    if let Err(_err) = respond(_stream, max_age, last_telegram, metrics).await {
+                        log!("Health check: request error: {}", _err);
+                    }
+                });
+            }
+            Err(_err) => log!("Health check: accept error: {}", _err),
+        }
+    }
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    max_age: Duration,
+    last_telegram: LastTelegram,
+    metrics: Metrics,
+) -> io::Result<()> {
+    // Only the request line is of interest, the rest of the request is ignored
+    let mut buf = [0u8; 1024];
+    let count = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..count]);
+
+    let (status, content_type, body) = if request.starts_with("GET /health ") {
+        let status = health_status(&last_telegram, max_age);
+        (status, "text/plain", status.to_string())
+    } else if request.starts_with("GET /metrics ") {
+        let body = metrics.lock().unwrap().clone();
+        ("200 OK", "text/plain; version=0.0.4", body)
+    } else {
+        ("404 Not Found", "text/plain", "404 Not Found".to_string())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown(Shutdown::Write)
+}
+
+// Status of `GET /health`, 200 if a telegram was parsed within `max_age` and 503 otherwise
+pub fn health_status(last_telegram: &LastTelegram, max_age: Duration) -> &'static str {
+    let healthy = match *last_telegram.lock().unwrap() {
+        Some(_last_telegram) => _last_telegram.elapsed() <= max_age,
+        None => false,
+    };
+    if healthy {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    }
+}
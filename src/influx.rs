@@ -0,0 +1,340 @@
+use crate::config::{Config, Precision};
+use crate::parse::tariff_label;
+use crate::telegram::{system_timestamp, Telegram};
+use crate::FieldValue;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io;
+use std::io::Write;
+use std::time::Duration;
+
+// Interval of the TCP keepalive probes on the connections to InfluxDB, so a connection that
+// silently dropped is noticed before it is reused
+pub const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+// Time to wait before the first retry of a failed post to InfluxDB, doubled for every next retry
+const POST_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Escape a measurement name for the line protocol, commas and spaces would end the name
+fn escape_measurement_name(name: &str) -> String {
+    name.replace(',', "\\,").replace(' ', "\\ ")
+}
+
+// Escape a tag key or value for the line protocol, commas, equals signs and spaces would end it
+fn escape_tag_value(value: &str) -> String {
+    value
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+// Value of an `--extra-field`, numbers are posted as numbers and anything else as a string. The
+// line protocol has no NaN or infinity, and a value with leading zeros (e.g. a postal code
+// `0123`) would lose them as a number.
+fn extra_field_value(value: &str) -> FieldValue {
+    let digits = value.trim_start_matches('-');
+    let leading_zero =
+        digits.len() > 1 && digits.starts_with('0') && digits.as_bytes()[1].is_ascii_digit();
+    if leading_zero {
+        return FieldValue::Str(value.to_string());
+    }
+    if let Ok(_value) = value.parse::<i64>() {
+        FieldValue::Int(_value)
+    } else {
+        match value.parse::<f64>() {
+            Ok(_value) if _value.is_finite() => FieldValue::Float(_value),
+            _ => FieldValue::Str(value.to_string()),
+        }
+    }
+}
+
+// Format the measurements of a telegram as InfluxDB line protocol, a line per measurement with
+// a `value` field, or in `--single-measurement` mode a single line with a field per measurement.
+// The lines are stamped with the time the meter measured, not the time they are posted, or the
+// system time if the telegram has no timestamp.
+pub fn line_protocol(config: &Config, telegram: &Telegram) -> Vec<String> {
+    let mut tags = format!("host={}", escape_tag_value(&config.host));
+    for (key, value) in &config.tags {
+        tags.push_str(&format!(
+            ",{}={}",
+            escape_tag_value(key),
+            escape_tag_value(value)
+        ));
+    }
+    if config.tariff_tag {
+        let tariff =
+            telegram
+                .get("currentTariff")
+                .and_then(|_measurement| match _measurement.value {
+                    FieldValue::Int(_tariff) => tariff_label(_tariff),
+                    _ => None,
+                });
+        if let Some(_tariff) = tariff {
+            tags.push_str(&format!(",tariff={}", _tariff));
+        }
+    }
+    let extra_fields: String = config
+        .extra_fields
+        .iter()
+        .map(|(_key, _value)| format!(",{}={}", escape_tag_value(_key), extra_field_value(_value)))
+        .collect();
+    let name =
+        |_name: &str| escape_measurement_name(&format!("{}{}", config.measurement_prefix, _name));
+    let now = system_timestamp();
+    let timestamp =
+        |_timestamp: Option<i64>| config.influx_precision.convert(_timestamp.unwrap_or(now));
+
+    match &config.single_measurement {
+        Some(_single_measurement) if !telegram.is_empty() => {
+            let fields: Vec<String> = telegram
+                .measurements()
+                .map(|_measurement| {
+                    format!(
+                        "{}={}",
+                        escape_tag_value(_measurement.name),
+                        _measurement.value
+                    )
+                })
+                .collect();
+            // The tags are shared by all fields, so a unit or device type is tagged with the
+            // name of its field
+            let mut tags = tags;
+            for measurement in telegram.measurements() {
+                if let Some(_unit) = &measurement.unit {
+                    tags.push_str(&format!(
+                        ",{}Unit={}",
+                        escape_tag_value(measurement.name),
+                        escape_tag_value(_unit)
+                    ));
+                }
+                if let Some(_device_type) = measurement.device_type {
+                    tags.push_str(&format!(
+                        ",{}DeviceType={}",
+                        escape_tag_value(measurement.name),
+                        _device_type
+                    ));
+                }
+            }
+            vec![format!(
+                "{},{} {}{} {}",
+                name(_single_measurement),
+                tags,
+                fields.join(","),
+                extra_fields,
+                timestamp(telegram.timestamp)
+            )]
+        }
+        Some(_) => Vec::new(),
+        None => telegram
+            .measurements()
+            .map(|_measurement| {
+                let unit = match &_measurement.unit {
+                    Some(_unit) => format!(",unit={}", escape_tag_value(_unit)),
+                    None => String::new(),
+                };
+                let device_type = match _measurement.device_type {
+                    Some(_device_type) => format!(",device_type={}", _device_type),
+                    None => String::new(),
+                };
+                format!(
+                    "{},{}{}{} value={}{} {}",
+                    name(_measurement.name),
+                    tags,
+                    unit,
+                    device_type,
+                    _measurement.value,
+                    extra_fields,
+                    timestamp(_measurement.timestamp)
+                )
+            })
+            .collect(),
+    }
+}
+
+// Read a PEM encoded root certificate to trust for HTTPS connections to InfluxDB
+pub fn read_certificate(path: &str) -> Result<reqwest::Certificate, String> {
+    let pem = std::fs::read(path).map_err(|_err| _err.to_string())?;
+    reqwest::Certificate::from_pem(&pem).map_err(|_err| _err.to_string())
+}
+
+// Add the `precision` parameter to an InfluxDB write URL, unless it already has one
+pub fn influx_write_url(url: &str, precision: Precision) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut _url) => {
+            if !_url.query_pairs().any(|(key, _)| key == "precision") {
+                _url.query_pairs_mut()
+                    .append_pair("precision", precision.as_str());
+            }
+            _url.to_string()
+        }
+        // Reported by the startup check or the first post
+        Err(_) => url.to_string(),
+    }
+}
+
+// Compress a line protocol body with gzip
+fn gzip(body: &str) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body.as_bytes())?;
+    encoder.finish()
+}
+
+// Outcome of a single post to InfluxDB
+enum PostResult {
+    Accepted,
+    // InfluxDB could accept it later, e.g. after a timeout or a 503 while it restarts
+    Retryable,
+    // Posting it again won't help, e.g. a 400 for a line InfluxDB can't parse
+    Rejected,
+}
+
+// Post line protocol lines to InfluxDB, returns whether InfluxDB accepted them. Timeouts,
+// connection errors and 5xx responses are retried up to `retries` times, waiting twice as long
+// before every next attempt.
+pub async fn post_influx_db(
+    client: &reqwest::Client,
+    url: &str,
+    request: String,
+    gzip: bool,
+    retries: u32,
+) -> bool {
+    verbose!("InfluxDB POST: {} {}", url, request);
+
+    let body = if gzip {
+        match self::gzip(&request) {
+            Ok(_body) => _body,
+            Err(_err) => {
+                log!("Error: could not compress request: {}", _err);
+                return false;
+            }
+        }
+    } else {
+        request.into_bytes()
+    };
+
+    let mut retry_delay = POST_RETRY_DELAY;
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            log!(
+                "Retrying the InfluxDB POST in {} ms ({}/{})",
+                retry_delay.as_millis(),
+                attempt,
+                retries
+            );
+            tokio::time::delay_for(retry_delay).await;
+            retry_delay *= 2;
+        }
+        match post_influx_db_once(client, url, body.clone(), gzip).await {
+            PostResult::Accepted => return true,
+            PostResult::Retryable => continue,
+            PostResult::Rejected => return false,
+        }
+    }
+    false
+}
+
+async fn post_influx_db_once(
+    client: &reqwest::Client,
+    url: &str,
+    body: Vec<u8>,
+    gzip: bool,
+) -> PostResult {
+    let request_builder = client.post(url);
+    let request_builder = if gzip {
+        request_builder.header(reqwest::header::CONTENT_ENCODING, "gzip")
+    } else {
+        request_builder
+    };
+
+    // Send request to InfluxDB
+    let response = request_builder.body(body).send().await;
+
+    // Handle success and error response
+    match response {
+        Ok(_response) => {
+            // Print if unexpected status code is received as response
+            if _response.status().to_string() != "204 No Content" {
+                log!("InfluxDB POST: Error Status: {}", _response.status());
+                if _response.status().is_server_error() {
+                    return PostResult::Retryable;
+                }
+                return PostResult::Rejected;
+            }
+            PostResult::Accepted
+        }
+        Err(_err) if _err.is_timeout() => {
+            log!("Request timed out: {}", _err);
+            PostResult::Retryable
+        }
+        Err(_err) => {
+            log!("Request error: {}", _err);
+            PostResult::Retryable
+        }
+    }
+}
+
+// Check InfluxDB is reachable through its `/ping` endpoint, returns the version it reports
+pub async fn ping_influx_db(client: &reqwest::Client, url: &str) -> Result<String, String> {
+    let mut ping_url = reqwest::Url::parse(url).map_err(|_err| format!("invalid URL: {}", _err))?;
+    // Both InfluxDB 1.x and 2.x serve `/ping` next to their write endpoint
+    ping_url.set_path("/ping");
+    ping_url.set_query(None);
+
+    let response = client
+        .get(ping_url)
+        .send()
+        .await
+        .map_err(|_err| _err.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("unexpected status {}", response.status()));
+    }
+    let version = response
+        .headers()
+        .get("X-Influxdb-Version")
+        .and_then(|_version| _version.to_str().ok())
+        .unwrap_or("unknown");
+    Ok(version.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extra_field_value_detects_numbers() {
+        assert_eq!(extra_field_value("42"), FieldValue::Int(42));
+        assert_eq!(extra_field_value("-1"), FieldValue::Int(-1));
+        assert_eq!(extra_field_value("0"), FieldValue::Int(0));
+        assert_eq!(extra_field_value("0.5"), FieldValue::Float(0.5));
+        assert_eq!(extra_field_value("1.25"), FieldValue::Float(1.25));
+        assert_eq!(
+            extra_field_value("garage"),
+            FieldValue::Str("garage".to_string())
+        );
+    }
+
+    #[test]
+    fn extra_field_value_keeps_non_finite_numbers_as_strings() {
+        for value in &["NaN", "nan", "inf", "-inf", "Infinity", "1e400"] {
+            assert_eq!(extra_field_value(value), FieldValue::Str(value.to_string()));
+        }
+    }
+
+    #[test]
+    fn extra_field_value_keeps_leading_zeros() {
+        for value in &["0123", "-0123", "00.5"] {
+            assert_eq!(extra_field_value(value), FieldValue::Str(value.to_string()));
+        }
+    }
+
+    #[test]
+    fn escapes_line_protocol_special_characters() {
+        assert_eq!(escape_measurement_name("p1 meter,hal"), "p1\\ meter\\,hal");
+        // An equals sign doesn't end a measurement name
+        assert_eq!(escape_measurement_name("p1=meter"), "p1=meter");
+        assert_eq!(
+            escape_tag_value("Hal boven,links=1"),
+            "Hal\\ boven\\,links\\=1"
+        );
+    }
+}
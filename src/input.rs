@@ -0,0 +1,137 @@
+use crate::framer::Framer;
+use mio::{Poll, PollOpt, Ready, Token};
+use std::io::{self, Read};
+use std::net;
+use std::time::Duration;
+use tokio::io::AsyncBufReadExt;
+use tokio::sync::mpsc;
+
+// USB vendor ids of the serial converters commonly used in P1 cables
+const P1_CABLE_VENDORS: &[(u16, &str)] = &[
+    (0x0403, "FTDI"),
+    (0x067b, "Prolific"),
+    (0x10c4, "Silicon Labs"),
+    (0x1a86, "QinHeng CH340"),
+];
+
+// Time to wait before retrying to connect to a network P1 reader
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// Source the telegrams are read from
+pub enum Input {
+    // Serial port the P1 cable is connected to
+    Serial(mio_serial::Serial),
+    // TCP socket of a network P1 reader, e.g. ser2net or an ESP32 running ESPHome
+    Tcp(mio::net::TcpStream),
+}
+
+impl Input {
+    pub fn register(&self, poll: &Poll, token: Token, interest: Ready) -> io::Result<()> {
+        match self {
+            Input::Serial(_serial) => poll.register(_serial, token, interest, PollOpt::edge()),
+            Input::Tcp(_stream) => poll.register(_stream, token, interest, PollOpt::edge()),
+        }
+    }
+
+    pub fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        match self {
+            Input::Serial(_serial) => poll.deregister(_serial),
+            Input::Tcp(_stream) => poll.deregister(_stream),
+        }
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::Serial(_serial) => _serial.read(buf),
+            Input::Tcp(_stream) => _stream.read(buf),
+        }
+    }
+}
+
+// Connect to a network P1 reader, retrying until it accepts the connection. Connecting blocks
+// (e.g. on a DNS lookup), so it's done on a blocking thread to keep the runtime going meanwhile.
+pub async fn connect_tcp(address: &str) -> mio::net::TcpStream {
+    loop {
+        let _address = address.to_string();
+        let connected = tokio::task::spawn_blocking(move || net::TcpStream::connect(_address))
+            .await
+            .expect("Connecting panicked");
+        match connected.and_then(mio::net::TcpStream::from_stream) {
+            Ok(_stream) => {
+                info!("Connected to {}", address);
+                return _stream;
+            }
+            Err(_err) => {
+                log!(
+                    "Could not connect to {}: {}, retrying in {} seconds",
+                    address,
+                    _err,
+                    RECONNECT_DELAY.as_secs()
+                );
+                tokio::time::delay_for(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+// Read telegrams piped into stdin until it is closed, e.g. a capture file
+pub async fn read_stdin(max_telegram_size: usize, mut telegrams: mpsc::Sender<String>) {
+    let mut stdin = tokio::io::BufReader::new(tokio::io::stdin());
+    let mut framer = Framer::new(max_telegram_size);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match stdin.read_until(b'\n', &mut line).await {
+            Ok(0) => return,
+            Ok(_) => {
+                for telegram in framer.push(&line) {
+                    if telegrams.send(telegram).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(_err) => {
+                log!("Error: could not read stdin: {}", _err);
+                return;
+            }
+        }
+    }
+}
+
+// Print the available serial ports, marking the ones that look like a P1 cable
+pub fn list_ports() -> Result<(), String> {
+    let ports = mio_serial::available_ports()
+        .map_err(|_err| format!("Could not list serial ports: {}", _err))?;
+    if ports.is_empty() {
+        println!("No serial ports found");
+    }
+
+    for port in ports {
+        match port.port_type {
+            serialport::SerialPortType::UsbPort(_info) => {
+                let vendor = P1_CABLE_VENDORS
+                    .iter()
+                    .find(|(_vid, _)| *_vid == _info.vid)
+                    .map(|(_, _name)| format!(" <- likely a P1 cable ({})", _name))
+                    .unwrap_or_default();
+                println!(
+                    "{} USB {:04x}:{:04x} {} {}{}",
+                    port.port_name,
+                    _info.vid,
+                    _info.pid,
+                    _info.manufacturer.as_deref().unwrap_or("-"),
+                    _info.product.as_deref().unwrap_or("-"),
+                    vendor
+                );
+            }
+            serialport::SerialPortType::PciPort => println!("{} PCI", port.port_name),
+            serialport::SerialPortType::BluetoothPort => {
+                println!("{} Bluetooth", port.port_name)
+            }
+            serialport::SerialPortType::Unknown => println!("{}", port.port_name),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,92 @@
+extern crate mio;
+extern crate mio_serial;
+
+use std::sync::atomic::AtomicBool;
+
+// Write log output to stderr instead of stdout, so stdout only carries `--json` output.
+// Rust's stdout is line buffered also when it isn't a terminal, so every line shows up in e.g.
+// `docker logs` right away.
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+macro_rules! log {
+    ($($arg:tt)*) => {
+        if crate::LOG_TO_STDERR.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+// Log startup and connection information, which `--quiet` leaves out so only warnings and
+// errors are logged
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if !crate::QUIET.load(std::sync::atomic::Ordering::Relaxed) {
+            log!($($arg)*);
+        }
+    };
+}
+
+// Log the details of every telegram (the telegram itself, parsed values, posts), only with
+// `--verbose`
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if crate::VERBOSE.load(std::sync::atomic::Ordering::Relaxed) {
+            log!($($arg)*);
+        }
+    };
+}
+
+mod adapter;
+mod capture;
+pub mod config;
+mod crc;
+pub mod framer;
+mod graphite;
+mod health;
+mod influx;
+mod input;
+mod measurements;
+mod metrics;
+pub mod obis;
+mod parse;
+mod rate;
+mod run;
+mod simulate;
+mod state;
+pub mod stats;
+pub mod telegram;
+
+pub use parse::parse;
+pub use run::run;
+
+use std::fmt;
+
+// Value of a measurement, formatted according to its InfluxDB field type
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Int(i64),
+    Str(String),
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldValue::Float(_value) => write!(f, "{}", _value),
+            // Integers need the `i` suffix, otherwise InfluxDB stores them as floats
+            FieldValue::Int(_value) => write!(f, "{}i", _value),
+            // Strings are quoted, with quotes and backslashes inside them escaped
+            FieldValue::Str(_value) => write!(
+                f,
+                "\"{}\"",
+                _value.replace('\\', "\\\\").replace('"', "\\\"")
+            ),
+        }
+    }
+}
@@ -0,0 +1,406 @@
+/*
+ * This adapter POSTs the following measurements to InfluxDB, as float fields unless noted otherwise
+ * - currentTariff - 1 or 2 (integer)
+ * - wattUsage - Current usage in Watt
+ * - wattUsageAccumulative - Current accumulative usage in kWh (sum of both tariffs)
+ * - wattUsageAccumulativeTariff1, wattUsageAccumulativeTariff2 - Current accumulative usage in kWh of tariff 1 (low) and 2 (high)
+ * - wattProduction - Current production in Watt
+ * - wattProductionAccumulative - Current accumulative produced in kWh (sum of both tariffs)
+ * - wattProductionAccumulativeTariff1, wattProductionAccumulativeTariff2 - Current accumulative produced in kWh of tariff 1 and 2
+ * - wattNett - Current nett power consumption in Watt (production minus usage, see --nett-sign)
+ * - gridPower - Current power drawn from the grid in Watt (usage minus production, negative while feeding back)
+ * - powerFlow - Current power in Watt, import positive and export negative (with --combined-power)
+ * - powerFlowAccumulative - Accumulative usage minus production in kWh (with --combined-power)
+ * - wattAccumulativeNett - Current accumulative nett power consumption in kWh (production minus usage, see --nett-sign)
+ * - gasUsageAccumulative - Current accumulative gas usage in m3 (also on DSMR 2.2/3.0 meters, converted from dm3 if needed)
+ *   tagged with the unit the meter reports, other M-Bus devices on the gas channel can report e.g. GJ
+ * - gasFlowRate - Gas usage in m3/h between the two most recent gas meter readings
+ * - energyUsedDelta - Usage in kWh since the previous post, only with --post-deltas
+ * - gasUsedDelta - Gas usage in m3 since the previous post, only with --post-deltas
+ * - gasStale - 1 when the gas meter stopped reporting, gas measurements aren't posted then, 0 otherwise (integer)
+ * - apparentPower - Current apparent power in VA. Only on meters that report it
+ * - reactivePowerImport, reactivePowerExport - Current reactive power in var. Only on meters that report it
+ * - currentQuarterAverage - Average power in W of the current quarter hour so far, the basis of the capacity tariff. Only on meters with a capacity tariff
+ * - monthlyPeakPower - Highest quarter hour average power in W of the current month. Only on meters with a capacity tariff
+ * - costElectricity - Cost of the accumulative usage, tariff 1 and 2 at their own price (with --price-tariff1 and --price-tariff2)
+ * - costGas - Cost of the accumulative gas usage (with --price-gas)
+ * - gasValveState - Valve state of the gas meter (integer), e.g. 0 closed, 1 open. Only on gas meters with a valve
+ * - breakerConnected - Breaker state (integer): 0 disconnected, 1 connected, 2 ready for reconnection. Only on meters that report it
+ * - limiterThreshold - Threshold limiter setting in kW (or A, depending on the meter), tagged with the unit the meter
+ *   reports. Only on meters that report it
+ * - failureEventCount - Number of power failures in the power failure event log (integer). Only on DSMR 4/5 meters
+ * - failureEvents - Number of power failures added to the log since the adapter started (integer), only goes up
+ * - lastFailureDuration - Duration in seconds of the most recent power failure in the log (integer)
+ * - mbusDeviceCount - Number of M-Bus devices (gas, water, ...) connected to the meter (integer)
+ * - mbusDeviceType1 - mbusDeviceType4 - M-Bus device type of the device on channel 1-4 (integer), e.g. 3 for gas or 7
+ *   for water. Only for the channels a device is connected to
+ * - wattUsageDerived - Usage in Watt derived from the accumulative usage (with --derive-power)
+ * - meterClockDrift - Telegram timestamp minus the system time on arrival in seconds (integer). Only when the telegram has a timestamp
+ * - adapterHeartbeat - Always 1 (integer), posted for every successfully parsed telegram
+ * - parseCompleteness - Percentage of the OBIS fields in the telegram that could be parsed, dips point at a bad cable
+ *
+ * The measurements read from an M-Bus device (gasUsageAccumulative, gasFlowRate, gasUsedDelta,
+ * costGas and gasValveState) are tagged with the device type of its channel as `device_type`, e.g.
+ * 3 for gas or 7 for water. They are read from the channel of the gas meter, or channel 1 when no
+ * device reports to be a gas meter.
+ */
+
+use crate::config::Config;
+
+// How the value of a measurement behaves over time, determines the Prometheus metric type
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricType {
+    // Goes up and down
+    Gauge,
+    // Only goes up, e.g. the accumulative energy counters
+    Counter,
+}
+
+// A measurement the adapter can post
+pub struct MeasurementDefinition {
+    pub name: &'static str,
+    pub metric_type: MetricType,
+    pub help: &'static str,
+}
+
+// All measurements the adapter can post, used to validate the selected measurements and to
+// describe the Prometheus metrics
+pub const MEASUREMENTS: &[MeasurementDefinition] = &[
+    MeasurementDefinition {
+        name: "currentTariff",
+        metric_type: MetricType::Gauge,
+        help: "Current tariff, 1 or 2",
+    },
+    MeasurementDefinition {
+        name: "wattUsage",
+        metric_type: MetricType::Gauge,
+        help: "Current usage in Watt",
+    },
+    MeasurementDefinition {
+        name: "wattUsageAccumulative",
+        metric_type: MetricType::Counter,
+        help: "Accumulative usage in kWh (sum of both tariffs)",
+    },
+    MeasurementDefinition {
+        name: "wattUsageAccumulativeTariff1",
+        metric_type: MetricType::Counter,
+        help: "Accumulative usage in kWh of tariff 1",
+    },
+    MeasurementDefinition {
+        name: "wattUsageAccumulativeTariff2",
+        metric_type: MetricType::Counter,
+        help: "Accumulative usage in kWh of tariff 2",
+    },
+    MeasurementDefinition {
+        name: "wattProduction",
+        metric_type: MetricType::Gauge,
+        help: "Current production in Watt",
+    },
+    MeasurementDefinition {
+        name: "wattProductionAccumulative",
+        metric_type: MetricType::Counter,
+        help: "Accumulative production in kWh (sum of both tariffs)",
+    },
+    MeasurementDefinition {
+        name: "wattProductionAccumulativeTariff1",
+        metric_type: MetricType::Counter,
+        help: "Accumulative production in kWh of tariff 1",
+    },
+    MeasurementDefinition {
+        name: "wattProductionAccumulativeTariff2",
+        metric_type: MetricType::Counter,
+        help: "Accumulative production in kWh of tariff 2",
+    },
+    MeasurementDefinition {
+        name: "wattNett",
+        metric_type: MetricType::Gauge,
+        help: "Current nett power consumption in Watt",
+    },
+    MeasurementDefinition {
+        name: "gridPower",
+        metric_type: MetricType::Gauge,
+        help: "Current power drawn from the grid in Watt, negative while feeding back",
+    },
+    MeasurementDefinition {
+        name: "wattAccumulativeNett",
+        metric_type: MetricType::Gauge,
+        help: "Accumulative nett power consumption in kWh",
+    },
+    MeasurementDefinition {
+        name: "powerFlow",
+        metric_type: MetricType::Gauge,
+        help: "Current power in Watt, import positive and export negative",
+    },
+    MeasurementDefinition {
+        name: "powerFlowAccumulative",
+        metric_type: MetricType::Gauge,
+        help: "Accumulative usage minus production in kWh",
+    },
+    MeasurementDefinition {
+        name: "gasUsageAccumulative",
+        metric_type: MetricType::Counter,
+        help: "Accumulative gas usage in m3",
+    },
+    MeasurementDefinition {
+        name: "gasFlowRate",
+        metric_type: MetricType::Gauge,
+        help: "Gas usage in m3/h between the two most recent gas meter readings",
+    },
+    MeasurementDefinition {
+        name: "gasStale",
+        metric_type: MetricType::Gauge,
+        help: "1 when the gas meter stopped reporting, 0 otherwise",
+    },
+    MeasurementDefinition {
+        name: "apparentPower",
+        metric_type: MetricType::Gauge,
+        help: "Current apparent power in VA",
+    },
+    MeasurementDefinition {
+        name: "reactivePowerImport",
+        metric_type: MetricType::Gauge,
+        help: "Current imported reactive power in var",
+    },
+    MeasurementDefinition {
+        name: "reactivePowerExport",
+        metric_type: MetricType::Gauge,
+        help: "Current exported reactive power in var",
+    },
+    MeasurementDefinition {
+        name: "currentQuarterAverage",
+        metric_type: MetricType::Gauge,
+        help: "Average power in W of the current quarter hour so far",
+    },
+    MeasurementDefinition {
+        name: "monthlyPeakPower",
+        metric_type: MetricType::Gauge,
+        help: "Highest quarter hour average power in W of the current month",
+    },
+    MeasurementDefinition {
+        name: "costElectricity",
+        metric_type: MetricType::Counter,
+        help: "Cost of the accumulative electricity usage at the configured tariff prices",
+    },
+    MeasurementDefinition {
+        name: "costGas",
+        metric_type: MetricType::Counter,
+        help: "Cost of the accumulative gas usage at the configured gas price",
+    },
+    MeasurementDefinition {
+        name: "gasValveState",
+        metric_type: MetricType::Gauge,
+        help: "Valve state of the gas meter",
+    },
+    MeasurementDefinition {
+        name: "wattUsageDerived",
+        metric_type: MetricType::Gauge,
+        help: "Usage in Watt derived from the accumulative usage",
+    },
+    MeasurementDefinition {
+        name: "breakerConnected",
+        metric_type: MetricType::Gauge,
+        help: "Breaker state: 0 disconnected, 1 connected, 2 ready for reconnection",
+    },
+    MeasurementDefinition {
+        name: "limiterThreshold",
+        metric_type: MetricType::Gauge,
+        help: "Threshold limiter setting in kW, or in A on meters that report it in A",
+    },
+    // The event log only holds the last few failures, so the count can go down
+    MeasurementDefinition {
+        name: "failureEventCount",
+        metric_type: MetricType::Gauge,
+        help: "Number of power failures in the power failure event log",
+    },
+    MeasurementDefinition {
+        name: "failureEvents",
+        metric_type: MetricType::Counter,
+        help: "Power failures added to the power failure event log since the adapter started",
+    },
+    MeasurementDefinition {
+        name: "lastFailureDuration",
+        metric_type: MetricType::Gauge,
+        help: "Duration in seconds of the most recent power failure",
+    },
+    MeasurementDefinition {
+        name: "mbusDeviceCount",
+        metric_type: MetricType::Gauge,
+        help: "Number of M-Bus devices connected to the meter",
+    },
+    MeasurementDefinition {
+        name: "mbusDeviceType1",
+        metric_type: MetricType::Gauge,
+        help: "M-Bus device type of the device on channel 1, e.g. 3 for gas or 7 for water",
+    },
+    MeasurementDefinition {
+        name: "mbusDeviceType2",
+        metric_type: MetricType::Gauge,
+        help: "M-Bus device type of the device on channel 2, e.g. 3 for gas or 7 for water",
+    },
+    MeasurementDefinition {
+        name: "mbusDeviceType3",
+        metric_type: MetricType::Gauge,
+        help: "M-Bus device type of the device on channel 3, e.g. 3 for gas or 7 for water",
+    },
+    MeasurementDefinition {
+        name: "mbusDeviceType4",
+        metric_type: MetricType::Gauge,
+        help: "M-Bus device type of the device on channel 4, e.g. 3 for gas or 7 for water",
+    },
+    MeasurementDefinition {
+        name: "meterClockDrift",
+        metric_type: MetricType::Gauge,
+        help: "Telegram timestamp minus the system time in seconds",
+    },
+    MeasurementDefinition {
+        name: "adapterHeartbeat",
+        metric_type: MetricType::Gauge,
+        help: "Always 1, posted for every successfully parsed telegram",
+    },
+    MeasurementDefinition {
+        name: "energyUsedDelta",
+        metric_type: MetricType::Gauge,
+        help: "Usage in kWh since the previous post",
+    },
+    MeasurementDefinition {
+        name: "gasUsedDelta",
+        metric_type: MetricType::Gauge,
+        help: "Gas usage in m3 since the previous post",
+    },
+    MeasurementDefinition {
+        name: "parseCompleteness",
+        metric_type: MetricType::Gauge,
+        help: "Percentage of the OBIS fields in the telegram that could be parsed",
+    },
+];
+
+// Compare measurement names ignoring case and underscores, so `watt_usage` matches `wattUsage`
+fn measurement_name_matches(measurement: &str, name: &str) -> bool {
+    let normalize = |_name: &str| _name.replace('_', "").to_lowercase();
+    normalize(measurement) == normalize(name)
+}
+
+// Whether a measurement is selected by `--measurements` and `--exclude-measurements`
+pub fn is_measurement_selected(config: &Config, measurement: &str) -> bool {
+    let included = match &config.measurements {
+        Some(_measurements) => _measurements
+            .iter()
+            .any(|_name| measurement_name_matches(measurement, _name)),
+        None => true,
+    };
+    included
+        && !config
+            .exclude_measurements
+            .iter()
+            .any(|_name| measurement_name_matches(measurement, _name))
+}
+
+// Names in `--measurements` and `--exclude-measurements` that match none of the measurements
+pub fn unknown_measurements(config: &Config) -> Vec<&str> {
+    config
+        .measurements
+        .iter()
+        .flatten()
+        .chain(&config.exclude_measurements)
+        .filter(|_name| {
+            !MEASUREMENTS
+                .iter()
+                .any(|_measurement| measurement_name_matches(_measurement.name, _name))
+        })
+        .map(|_name| _name.as_str())
+        .collect()
+}
+
+// Instantaneous power measurements, accumulative counters always use the latest value
+pub const INSTANTANEOUS_MEASUREMENTS: &[&str] =
+    &["wattUsage", "wattProduction", "wattNett", "gridPower"];
+
+// Accumulative counters, these only change when energy or gas was used or produced
+pub const ACCUMULATIVE_MEASUREMENTS: &[&str] = &[
+    "wattUsageAccumulative",
+    "wattUsageAccumulativeTariff1",
+    "wattUsageAccumulativeTariff2",
+    "wattProductionAccumulative",
+    "wattProductionAccumulativeTariff1",
+    "wattProductionAccumulativeTariff2",
+    "wattAccumulativeNett",
+    "gasUsageAccumulative",
+];
+
+// Measurements calculated from others, with the measurements they are calculated from. These are
+// dropped along with an implausible value they are based on.
+pub const DERIVED_MEASUREMENTS: &[(&str, &[&str])] = &[
+    ("wattNett", &["wattUsage", "wattProduction"]),
+    ("gridPower", &["wattUsage", "wattProduction"]),
+    ("powerFlow", &["wattUsage", "wattProduction"]),
+    (
+        "wattAccumulativeNett",
+        &["wattUsageAccumulative", "wattProductionAccumulative"],
+    ),
+    (
+        "powerFlowAccumulative",
+        &["wattUsageAccumulative", "wattProductionAccumulative"],
+    ),
+    (
+        "costElectricity",
+        &[
+            "wattUsageAccumulativeTariff1",
+            "wattUsageAccumulativeTariff2",
+        ],
+    ),
+    ("costGas", &["gasUsageAccumulative"]),
+];
+
+// Measurements that show the adapter is alive, these are posted with every telegram also with
+// `--post-on-change-only`
+pub const LIVENESS_MEASUREMENTS: &[&str] = &["adapterHeartbeat"];
+
+// Measurements based on the gas reading, these aren't posted when the gas meter stopped reporting
+pub const GAS_MEASUREMENTS: &[&str] = &["gasUsageAccumulative", "costGas"];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_posts_the_selected_measurements() {
+        let names = |_names: &[&str]| _names.iter().map(|_name| _name.to_string()).collect();
+        let included = Config {
+            measurements: Some(names(&["wattUsage", "gasUsageAccumulative"])),
+            ..Config::default()
+        };
+        assert!(is_measurement_selected(&included, "wattUsage"));
+        assert!(is_measurement_selected(&included, "gasUsageAccumulative"));
+        assert!(!is_measurement_selected(&included, "wattProduction"));
+
+        let excluded = Config {
+            exclude_measurements: names(&["wattUsage"]),
+            ..Config::default()
+        };
+        assert!(!is_measurement_selected(&excluded, "wattUsage"));
+        assert!(is_measurement_selected(&excluded, "wattProduction"));
+
+        // Snake case names match the measurement regardless of case and underscores
+        let snake_case = Config {
+            measurements: Some(names(&["watt_usage", "GAS_USAGE_ACCUMULATIVE"])),
+            exclude_measurements: names(&["watt_usage"]),
+            ..Config::default()
+        };
+        assert!(!is_measurement_selected(&snake_case, "wattUsage"));
+        assert!(is_measurement_selected(&snake_case, "gasUsageAccumulative"));
+        assert!(!is_measurement_selected(
+            &snake_case,
+            "wattUsageAccumulative"
+        ));
+        assert_eq!(unknown_measurements(&snake_case), Vec::<&str>::new());
+
+        // A name has to match a measurement as a whole
+        let unknown = Config {
+            measurements: Some(names(&["watt_usage", "gas_usage"])),
+            exclude_measurements: names(&["voltage"]),
+            ..Config::default()
+        };
+        assert_eq!(unknown_measurements(&unknown), ["gas_usage", "voltage"]);
+        assert!(!is_measurement_selected(&unknown, "gasUsageAccumulative"));
+    }
+}
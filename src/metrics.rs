@@ -0,0 +1,150 @@
+use crate::measurements::{MetricType, MEASUREMENTS};
+use crate::stats::Stats;
+use crate::FieldValue;
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+// Latest measurements in the Prometheus text exposition format, served on `/metrics`
+pub type Metrics = Arc<Mutex<String>>;
+
+// Prefix of all metric names, so they don't collide with those of other exporters
+const METRIC_PREFIX: &str = "dsmr_";
+
+// Render the measurements of the latest telegram and the adapter statistics as Prometheus
+// metrics, with the type and description of each metric
+pub fn render(measurements: &[(&'static str, FieldValue)], stats: &Stats) -> String {
+    let mut output = String::new();
+
+    for (key, value) in measurements {
+        let value = match value {
+            FieldValue::Float(_value) => _value.to_string(),
+            FieldValue::Int(_value) => _value.to_string(),
+            // Prometheus only has numeric values
+            FieldValue::Str(_) => continue,
+        };
+        let (metric_type, help) = match MEASUREMENTS
+            .iter()
+            .find(|_measurement| _measurement.name == *key)
+        {
+            Some(_measurement) => (_measurement.metric_type, _measurement.help),
+            None => (MetricType::Gauge, *key),
+        };
+        write_metric(&mut output, &metric_name(key), metric_type, help, &value);
+    }
+
+    let counters = [
+        (
+            "telegrams",
+            "Complete telegrams received from the meter",
+            stats.telegrams,
+        ),
+        (
+            "invalid_telegrams",
+            "Telegrams from which nothing could be parsed",
+            stats.invalid_telegrams,
+        ),
+        (
+            "crc_errors",
+            "Telegrams whose CRC didn't match",
+            stats.crc_errors,
+        ),
+        (
+            "dropped_telegrams",
+            "Telegrams dropped because processing couldn't keep up",
+            stats.dropped_telegrams.load(Ordering::Relaxed),
+        ),
+        (
+            "parse_errors",
+            "Measurements that could not be parsed",
+            stats.parse_errors.values().sum(),
+        ),
+        (
+            "posts",
+            "Line protocol lines posted to InfluxDB",
+            stats.posts,
+        ),
+        (
+            "failed_posts",
+            "Line protocol lines InfluxDB did not accept or that could not be sent",
+            stats.failed_posts,
+        ),
+    ];
+    for (name, help, value) in &counters {
+        let name = format!("{}adapter_{}", METRIC_PREFIX, name);
+        write_metric(
+            &mut output,
+            &name,
+            MetricType::Counter,
+            help,
+            &value.to_string(),
+        );
+    }
+    output
+}
+
+fn write_metric(output: &mut String, name: &str, metric_type: MetricType, help: &str, value: &str) {
+    // Counters get the `_total` suffix the OpenMetrics format requires
+    let (name, metric_type) = match metric_type {
+        MetricType::Gauge => (name.to_string(), "gauge"),
+        MetricType::Counter => (format!("{}_total", name), "counter"),
+    };
+    // Writing to a String can not fail
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, metric_type);
+    let _ = writeln!(output, "{} {}", name, value);
+}
+
+// Convert a measurement name to a metric name, e.g. `wattUsage` to `dsmr_watt_usage`
+fn metric_name(key: &str) -> String {
+    let mut name = METRIC_PREFIX.to_string();
+    for char in key.chars() {
+        if char.is_ascii_uppercase() {
+            name.push('_');
+        }
+        name.push(char.to_ascii_lowercase());
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_typed_as_gauges_and_counters() {
+        let stats = Stats {
+            telegrams: 3,
+            ..Stats::default()
+        };
+        let output = render(
+            &[
+                ("wattUsage", FieldValue::Float(131.0)),
+                ("wattUsageAccumulative", FieldValue::Float(14531.932)),
+                // Goes down when more is produced than used
+                ("wattAccumulativeNett", FieldValue::Float(-8290.431)),
+                // The event log only holds the last few failures
+                ("failureEventCount", FieldValue::Int(2)),
+                ("failureEvents", FieldValue::Int(1)),
+            ],
+            &stats,
+        );
+        for _metric in &[
+            "# HELP dsmr_watt_usage Current usage in Watt\n\
+             # TYPE dsmr_watt_usage gauge\n\
+             dsmr_watt_usage 131\n",
+            "# TYPE dsmr_watt_usage_accumulative_total counter\n\
+             dsmr_watt_usage_accumulative_total 14531.932\n",
+            "# TYPE dsmr_watt_accumulative_nett gauge\n\
+             dsmr_watt_accumulative_nett -8290.431\n",
+            "# TYPE dsmr_failure_event_count gauge\n\
+             dsmr_failure_event_count 2\n",
+            "# TYPE dsmr_failure_events_total counter\n\
+             dsmr_failure_events_total 1\n",
+            "# TYPE dsmr_adapter_telegrams_total counter\n\
+             dsmr_adapter_telegrams_total 3\n",
+        ] {
+            assert!(output.contains(_metric), "{}", output);
+        }
+    }
+}
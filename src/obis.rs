@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::fmt;
+
+// Why the value of an OBIS id couldn't be read. A missing id is expected for the ids not every
+// meter has (e.g. a gas valve), the parsers tell it apart from values that are there but invalid.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObisError {
+    // The telegram doesn't have the id
+    Missing,
+    // The id is there, but without values
+    NoValues,
+    // The values can't be parsed
+    Invalid(&'static str),
+}
+
+impl ObisError {
+    fn message(self) -> &'static str {
+        match self {
+            ObisError::Missing => "Index not found",
+            ObisError::NoValues => "Values not found",
+            ObisError::Invalid(_message) => _message,
+        }
+    }
+}
+
+impl fmt::Display for ObisError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl From<&'static str> for ObisError {
+    fn from(message: &'static str) -> ObisError {
+        ObisError::Invalid(message)
+    }
+}
+
+// For the parsers that only report what went wrong
+impl From<ObisError> for &'static str {
+    fn from(err: ObisError) -> &'static str {
+        err.message()
+    }
+}
+
+// The values of the OBIS ids of a telegram, split up once so the parsers don't each scan the
+// whole telegram again. E.g. `1-0:99.97.0(2)(0-0:96.7.19)(181206112732W)` has the values `2`,
+// `0-0:96.7.19` and `181206112732W`.
+pub struct ObisValues<'a> {
+    // The values of every line of an id, in the order of the telegram. Some meters send an id on
+    // more than one line, e.g. the gas reading of some DSMR 4 meters.
+    lines: HashMap<&'a str, Vec<Vec<&'a str>>>,
+    // The ids in the order they first occur in the telegram
+    ids: Vec<&'a str>,
+}
+
+impl<'a> ObisValues<'a> {
+    pub fn parse(telegram: &'a str) -> ObisValues<'a> {
+        let mut lines: HashMap<&str, Vec<Vec<&str>>> = HashMap::new();
+        let mut ids = Vec::new();
+        let mut previous_id = None;
+        for line in telegram.lines() {
+            // Lines without values (the header, the CRC) don't have an id
+            let index = match line.find('(') {
+                Some(_index) => _index,
+                None => continue,
+            };
+            let id = &line[..index];
+            let values = line[index..].split(['(', ')']).filter(|x| !x.is_empty());
+
+            // DSMR 2.2 and 3.0 meters put the gas reading on the line after its id, e.g.:
+            //
+            //   0-1:24.3.0(121221170000)(00)(60)(1)(0-1:24.2.1)(m3)
+            //   (04312.372)
+            if id.is_empty() {
+                let previous_values = previous_id
+                    .and_then(|_id| lines.get_mut(_id))
+                    .and_then(|_lines: &mut Vec<Vec<&str>>| _lines.last_mut());
+                if let Some(_previous_values) = previous_values {
+                    _previous_values.extend(values);
+                }
+                continue;
+            }
+
+            if !lines.contains_key(id) {
+                ids.push(id);
+            }
+            lines.entry(id).or_default().push(values.collect());
+            previous_id = Some(id);
+        }
+        ObisValues { lines, ids }
+    }
+
+    // The values of an id, of its first line if it's on more than one
+    pub fn get(&self, id: &str) -> Result<&[&'a str], ObisError> {
+        match self.lines.get(id).and_then(|_lines| _lines.first()) {
+            Some(_values) if !_values.is_empty() => Ok(_values),
+            Some(_) => Err(ObisError::NoValues),
+            None => Err(ObisError::Missing),
+        }
+    }
+
+    // The values of every line of an id
+    pub fn get_all(&self, id: &str) -> &[Vec<&'a str>] {
+        self.lines.get(id).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    // Every id with the values of each of its lines, in the order of the telegram
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &[Vec<&'a str>])> + '_ {
+        self.ids.iter().map(move |_id| (*_id, self.get_all(_id)))
+    }
+}
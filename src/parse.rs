@@ -0,0 +1,1437 @@
+use crate::config::{Config, MeterProfile};
+use crate::obis::{ObisError, ObisValues};
+use crate::stats::Stats;
+use crate::telegram::Telegram;
+use crate::FieldValue;
+use chrono::prelude::NaiveDateTime;
+use std::borrow::Cow;
+
+// Print every OBIS id of a telegram with its values, and the measurements the adapter reads from
+// it if any, e.g. `1-0:1.7.0 (00.131*kW) wattUsage`. Shows which ids an unknown meter sends.
+pub fn print_obis_dump(profile: MeterProfile, obis: &ObisValues<'_>) {
+    for (id, lines) in obis.iter() {
+        let names: Vec<&str> = obis_map(profile)
+            .iter()
+            .filter(|_definition| _definition.id == id)
+            .map(|_definition| _definition.name)
+            .collect();
+        for values in lines {
+            let values: String = values
+                .iter()
+                .map(|_value| format!("({})", _value))
+                .collect();
+            println!(
+                "{}",
+                format!("{} {} {}", id, values, names.join(",")).trim_end()
+            );
+        }
+    }
+    println!();
+}
+
+// Remove the unit (e.g. `*kW`) from a value
+fn strip_unit(value: &str) -> &str {
+    match value.rfind('*') {
+        Some(_index) => &value[.._index],
+        None => value,
+    }
+}
+
+// Split a value into its number and unit (empty if it has none), e.g. `0001.727*kW`. The unit
+// follows the last `*`, a corrupted read can have more (e.g. `123*456*kWh`), so the number has to
+// consist of only digits, a decimal separator and a sign. Belgian (Fluvius) meters can use a
+// comma as decimal separator, the number is returned with a decimal point.
+fn split_unit(value: &str) -> Result<(Cow<'_, str>, &str), &'static str> {
+    let (number, unit) = value.rsplit_once('*').unwrap_or((value, ""));
+    let digits = number.strip_prefix('-').unwrap_or(number);
+    let is_separator = |_char: char| _char == '.' || _char == ',';
+    let is_number = digits.chars().any(|_char| _char.is_ascii_digit())
+        && digits
+            .chars()
+            .all(|_char| _char.is_ascii_digit() || is_separator(_char))
+        && digits.matches(is_separator).count() <= 1;
+    if !is_number {
+        return Err("Invalid number");
+    }
+    if number.contains(',') {
+        return Ok((Cow::Owned(number.replace(',', ".")), unit));
+    }
+    Ok((Cow::Borrowed(number), unit))
+}
+
+// Parse a DSMR timestamp value (YYMMDDhhmmss followed by a DST marker) to a Unix timestamp.
+// The time is the local Dutch time, `W` marks winter time (CET, UTC+1) and `S` summer time
+// (CEST, UTC+2). DSMR 2.2/3.0 meters don't add a marker, their time is taken as winter time.
+fn parse_dsmr_timestamp(value: &str) -> Result<i64, &'static str> {
+    let (_value, utc_offset) = if let Some(_value) = value.strip_suffix('S') {
+        (_value, 2 * 3600)
+    } else if let Some(_value) = value.strip_suffix('W') {
+        (_value, 3600)
+    } else {
+        (value, 3600)
+    };
+    match NaiveDateTime::parse_from_str(_value, "%y%m%d%H%M%S") {
+        Ok(_timestamp) => Ok(_timestamp.timestamp() - utc_offset),
+        Err(_) => Err("Could not parse timestamp"),
+    }
+}
+
+// Parse timestamp from telegram
+async fn parse_timestamp(obis: &ObisValues<'_>) -> Result<i64, &'static str> {
+    let values = obis.get("0-0:1.0.0")?;
+    let timestamp = values.first();
+    match timestamp {
+        Some(_timestamp) => parse_dsmr_timestamp(_timestamp),
+        None => Err("Could not read timestamp"),
+    }
+}
+
+// Convert kW to W, rounded to whole Watts (the resolution of the meter) to drop floating point noise.
+// The sign is kept, so meters reporting export as a negative reading (e.g. `-00.131*kW`) post a
+// negative value, rounding is symmetric around zero. Adding 0 turns a `-0` (e.g. from
+// `-00.000*kW`) into a plain `0`.
+fn kw_to_w(kw: f64) -> f64 {
+    (kw * 1000.0).round() + 0.0
+}
+
+// Round kWh to Wh, the resolution of the meter, to drop the floating point noise of adding up
+// registers (e.g. 100.1 + 200.2 = 300.29999999999995)
+pub fn round_kwh(kwh: f64) -> f64 {
+    (kwh * 1000.0).round() / 1000.0
+}
+
+// Parse a single energy register in kWh, e.g. the usage of one tariff
+fn parse_kwh(id: &str, obis: &ObisValues) -> Result<f64, ObisError> {
+    let values = obis.get(id)?;
+    match values.first() {
+        Some(_value) => match split_unit(_value)? {
+            (_number, "kWh") | (_number, "") => Ok(_number
+                .parse::<f64>()
+                .map_err(|_| "Could not parse energy")?),
+            _ => Err(ObisError::Invalid("Unexpected unit")),
+        },
+        None => Err(ObisError::NoValues),
+    }
+}
+
+// Calculate the cost of the accumulative electricity usage, each tariff at its own price
+async fn calculate_cost_electricity(
+    obis: &ObisValues<'_>,
+    price_tariff_1: f64,
+    price_tariff_2: f64,
+) -> Result<f64, &'static str> {
+    let usage_tariff_1 = parse_kwh("1-0:1.8.1", obis)?;
+    let usage_tariff_2 = parse_kwh("1-0:1.8.2", obis)?;
+    Ok(usage_tariff_1 * price_tariff_1 + usage_tariff_2 * price_tariff_2)
+}
+
+// Parse the DSMR version of the telegram, e.g. 50 for DSMR 5.0. Belgian meters report the
+// version of their DSMR 5 based spec as 0-0:96.1.4 instead, e.g. `50217` for 5.0.2.17.
+pub async fn parse_protocol_version(obis: &ObisValues<'_>) -> Result<i64, &'static str> {
+    let value = match (obis.get("1-3:0.2.8"), obis.get("0-0:96.1.4")) {
+        (Ok(_values), _) => _values.first().copied(),
+        (Err(_), Ok(_values)) => _values.first().and_then(|_value| _value.get(..2)),
+        (Err(_err), Err(_)) => return Err(_err.into()),
+    };
+    match value {
+        Some(_value) => _value
+            .parse::<i64>()
+            .map_err(|_| "Could not parse protocol version"),
+        None => Err("Could not read protocol version"),
+    }
+}
+
+// How the values of an OBIS id are turned into a measurement
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ObisTransform {
+    // Whole number, e.g. a state
+    Int,
+    // Number in the unit reported by the meter
+    Float,
+    // Power in kilo units (e.g. kW or kVA), converted to whole units
+    Kilo,
+    // Current tariff, 1 or 2
+    Tariff,
+    // Energy in kWh, summed with the energy of tariff 2 under the given id
+    SumOfTariffs(&'static str),
+    // Gas reading, which has a different id and format depending on the meter
+    Gas,
+    // Power in W or kW, depending on the meter, converted to W
+    Power,
+    // Energy in Wh or kWh, depending on the meter, converted to kWh
+    Energy,
+}
+
+// An OBIS id the adapter reads a measurement from
+struct ObisDefinition {
+    id: &'static str,
+    name: &'static str,
+    // Unit the value is suffixed with, e.g. `*kW`, empty to accept any unit
+    unit: &'static str,
+    transform: ObisTransform,
+    // Whether every meter reports it, it's no parse error when another id is missing. The gas
+    // reading is only expected when the telegram reports a gas meter on its M-Bus.
+    required: bool,
+}
+
+// The OBIS ids that are read from every telegram, in the order they are posted. Measurements
+// calculated from these (e.g. wattNett) and ids with a more complex format (e.g. the power failure
+// log) are parsed in `parse_telegram`.
+const OBIS_MAP: &[ObisDefinition] = &[
+    ObisDefinition {
+        id: "0-0:96.14.0",
+        name: "currentTariff",
+        unit: "",
+        transform: ObisTransform::Tariff,
+        required: true,
+    },
+    ObisDefinition {
+        id: "1-0:1.7.0",
+        name: "wattUsage",
+        unit: "*kW",
+        transform: ObisTransform::Kilo,
+        required: true,
+    },
+    ObisDefinition {
+        id: "1-0:1.8.1",
+        name: "wattUsageAccumulative",
+        unit: "*kWh",
+        transform: ObisTransform::SumOfTariffs("1-0:1.8.2"),
+        required: true,
+    },
+    ObisDefinition {
+        id: "1-0:1.8.1",
+        name: "wattUsageAccumulativeTariff1",
+        unit: "*kWh",
+        transform: ObisTransform::Float,
+        required: false,
+    },
+    ObisDefinition {
+        id: "1-0:1.8.2",
+        name: "wattUsageAccumulativeTariff2",
+        unit: "*kWh",
+        transform: ObisTransform::Float,
+        required: false,
+    },
+    ObisDefinition {
+        id: "1-0:2.7.0",
+        name: "wattProduction",
+        unit: "*kW",
+        transform: ObisTransform::Kilo,
+        required: true,
+    },
+    ObisDefinition {
+        id: "1-0:2.8.1",
+        name: "wattProductionAccumulative",
+        unit: "*kWh",
+        transform: ObisTransform::SumOfTariffs("1-0:2.8.2"),
+        required: true,
+    },
+    ObisDefinition {
+        id: "1-0:2.8.1",
+        name: "wattProductionAccumulativeTariff1",
+        unit: "*kWh",
+        transform: ObisTransform::Float,
+        required: false,
+    },
+    ObisDefinition {
+        id: "1-0:2.8.2",
+        name: "wattProductionAccumulativeTariff2",
+        unit: "*kWh",
+        transform: ObisTransform::Float,
+        required: false,
+    },
+    ObisDefinition {
+        id: "0-1:24.2.1",
+        name: "gasUsageAccumulative",
+        unit: "*m3",
+        transform: ObisTransform::Gas,
+        required: false,
+    },
+    // Only some (three phase) industrial meters report apparent and reactive power
+    ObisDefinition {
+        id: "1-0:9.7.0",
+        name: "apparentPower",
+        unit: "*kVA",
+        transform: ObisTransform::Kilo,
+        required: false,
+    },
+    ObisDefinition {
+        id: "1-0:3.7.0",
+        name: "reactivePowerImport",
+        unit: "*kvar",
+        transform: ObisTransform::Kilo,
+        required: false,
+    },
+    ObisDefinition {
+        id: "1-0:4.7.0",
+        name: "reactivePowerExport",
+        unit: "*kvar",
+        transform: ObisTransform::Kilo,
+        required: false,
+    },
+    // Only meters with a capacity tariff report the quarter hour average and its monthly peak
+    ObisDefinition {
+        id: "1-0:1.4.0",
+        name: "currentQuarterAverage",
+        unit: "*kW",
+        transform: ObisTransform::Kilo,
+        required: false,
+    },
+    ObisDefinition {
+        id: "1-0:1.6.0",
+        name: "monthlyPeakPower",
+        unit: "*kW",
+        transform: ObisTransform::Kilo,
+        required: false,
+    },
+    // Most consumer meters don't report the breaker state or a threshold limiter
+    ObisDefinition {
+        id: "0-0:96.3.10",
+        name: "breakerConnected",
+        unit: "",
+        transform: ObisTransform::Int,
+        required: false,
+    },
+    ObisDefinition {
+        id: "0-0:17.0.0",
+        name: "limiterThreshold",
+        unit: "",
+        transform: ObisTransform::Float,
+        required: false,
+    },
+];
+
+// The OBIS ids of `--meter-profile nordic`, Swedish and Norwegian meters with a HAN port. These
+// have a single tariff and no gas meter, and report power and energy in W/Wh or kW/kWh.
+const NORDIC_OBIS_MAP: &[ObisDefinition] = &[
+    ObisDefinition {
+        id: "1-0:1.7.0",
+        name: "wattUsage",
+        unit: "",
+        transform: ObisTransform::Power,
+        required: true,
+    },
+    ObisDefinition {
+        id: "1-0:1.8.0",
+        name: "wattUsageAccumulative",
+        unit: "",
+        transform: ObisTransform::Energy,
+        required: true,
+    },
+    ObisDefinition {
+        id: "1-0:2.7.0",
+        name: "wattProduction",
+        unit: "",
+        transform: ObisTransform::Power,
+        required: true,
+    },
+    ObisDefinition {
+        id: "1-0:2.8.0",
+        name: "wattProductionAccumulative",
+        unit: "",
+        transform: ObisTransform::Energy,
+        required: true,
+    },
+    ObisDefinition {
+        id: "1-0:3.7.0",
+        name: "reactivePowerImport",
+        unit: "*kvar",
+        transform: ObisTransform::Kilo,
+        required: false,
+    },
+    ObisDefinition {
+        id: "1-0:4.7.0",
+        name: "reactivePowerExport",
+        unit: "*kvar",
+        transform: ObisTransform::Kilo,
+        required: false,
+    },
+];
+
+// The OBIS ids read from the telegrams of a meter profile
+fn obis_map(profile: MeterProfile) -> &'static [ObisDefinition] {
+    match profile {
+        MeterProfile::Dsmr => OBIS_MAP,
+        MeterProfile::Nordic => NORDIC_OBIS_MAP,
+    }
+}
+
+// Read the measurement of an OBIS id from a telegram. The value is the last one of the id, it is
+// preceded by e.g. the time of a peak, as in `1-0:1.6.0(200509134558S)(02.589*kW)`.
+async fn parse_obis(
+    definition: &ObisDefinition,
+    obis: &ObisValues<'_>,
+    mbus: &MbusReadings,
+) -> Result<FieldValue, ObisError> {
+    match definition.transform {
+        ObisTransform::SumOfTariffs(_tariff_2_id) => {
+            let tariff_1 = parse_kwh(definition.id, obis)?;
+            let tariff_2 = parse_kwh(_tariff_2_id, obis)?;
+            return Ok(FieldValue::Float(round_kwh(tariff_1 + tariff_2)));
+        }
+        ObisTransform::Gas => {
+            let (_, gas_usage, _) = mbus.gas.clone()?;
+            return Ok(FieldValue::Float(gas_usage));
+        }
+        _ => {}
+    }
+
+    let values = obis.get(definition.id)?;
+    let value = values.last().ok_or("Could not read value")?;
+    if let ObisTransform::Power | ObisTransform::Energy = definition.transform {
+        let (value, unit) = split_unit(value)?;
+        let value = value.parse::<f64>().map_err(|_| "Could not parse value")?;
+        return match (definition.transform, unit) {
+            (ObisTransform::Power, "W") => Ok(FieldValue::Float(value)),
+            (ObisTransform::Power, "kW") => Ok(FieldValue::Float(kw_to_w(value))),
+            (ObisTransform::Energy, "Wh") => Ok(FieldValue::Float(round_kwh(value / 1000.0))),
+            (ObisTransform::Energy, "kWh") => Ok(FieldValue::Float(value)),
+            _ => Err(ObisError::Invalid("Unexpected unit")),
+        };
+    }
+    let value = match (definition.unit, split_unit(value)?) {
+        ("", (_number, _)) => _number,
+        (_unit, (_number, _value_unit)) if _unit.strip_prefix('*') == Some(_value_unit) => _number,
+        _ => return Err(ObisError::Invalid("Unexpected unit")),
+    };
+    // Number parsing doesn't depend on the locale and takes values without an integer part or
+    // fraction as well, e.g. `.131` or `0` from meters that leave out the zero padding
+    let invalid = |_message| ObisError::Invalid(_message);
+    match definition.transform {
+        ObisTransform::Int => value
+            .parse::<i64>()
+            .map(FieldValue::Int)
+            .map_err(|_| invalid("Could not parse value")),
+        ObisTransform::Float => value
+            .parse::<f64>()
+            .map(FieldValue::Float)
+            .map_err(|_| invalid("Could not parse value")),
+        ObisTransform::Kilo => value
+            .parse::<f64>()
+            .map(|_value| FieldValue::Float(kw_to_w(_value)))
+            .map_err(|_| invalid("Could not parse value")),
+        // Zero padded, e.g. `0002`
+        ObisTransform::Tariff => match value.parse::<i64>() {
+            Ok(_tariff) if tariff_label(_tariff).is_some() => Ok(FieldValue::Int(_tariff)),
+            Ok(_) => Err(invalid("Unknown current tariff")),
+            Err(_) => Err(invalid("Could not parse current tariff")),
+        },
+        ObisTransform::SumOfTariffs(_)
+        | ObisTransform::Gas
+        | ObisTransform::Power
+        | ObisTransform::Energy => unreachable!(),
+    }
+}
+
+// Value of a float measurement among the measurements of a telegram
+pub fn float_measurement(measurements: &[(&'static str, FieldValue)], name: &str) -> Option<f64> {
+    measurements.iter().find_map(|(key, value)| match value {
+        FieldValue::Float(_value) if *key == name => Some(*_value),
+        _ => None,
+    })
+}
+
+// Label of a tariff, Dutch meters use tariff 1 for the low (night and weekend) and tariff 2 for
+// the normal (high) rate
+pub fn tariff_label(tariff: i64) -> Option<&'static str> {
+    match tariff {
+        1 => Some("low"),
+        2 => Some("high"),
+        _ => None,
+    }
+}
+
+// Parse the power failure event log, returns the number of logged failures and the (end time,
+// duration in seconds) of every failure. The log is a count followed by the OBIS id of the
+// duration and a (end time)(duration) pair per failure, e.g.:
+//
+//   1-0:99.97.0(2)(0-0:96.7.19)(181206112732W)(0000007692*s)(000101000001W)(2147483647*s)
+pub async fn parse_power_failure_log(
+    obis: &ObisValues<'_>,
+) -> Result<(i64, Vec<(i64, i64)>), ObisError> {
+    let values = obis.get("1-0:99.97.0")?;
+    let count = match values.first() {
+        Some(_count) => _count
+            .parse::<i64>()
+            .map_err(|_| "Could not parse power failure count")?,
+        None => return Err(ObisError::NoValues),
+    };
+
+    // Entries aren't necessarily in order, and unused entries hold placeholder values
+    let entries = values
+        .get(2..)
+        .unwrap_or(&[])
+        .chunks(2)
+        .filter_map(|_entry| match _entry {
+            [_end_time, _duration] => {
+                let end_time = parse_dsmr_timestamp(_end_time).ok()?;
+                let duration = strip_unit(_duration).parse::<i64>().ok()?;
+                Some((end_time, duration))
+            }
+            _ => None,
+        })
+        .collect();
+    Ok((count, entries))
+}
+
+// Measurements read from the M-Bus device on the channel of the gas meter
+const GAS_CHANNEL_MEASUREMENTS: [&str; 5] = [
+    "gasUsageAccumulative",
+    "gasFlowRate",
+    "gasUsedDelta",
+    "costGas",
+    "gasValveState",
+];
+
+// Measurements the device type of the M-Bus device on channel 1-4 is posted as
+const MBUS_DEVICE_TYPE_MEASUREMENTS: [&str; 4] = [
+    "mbusDeviceType1",
+    "mbusDeviceType2",
+    "mbusDeviceType3",
+    "mbusDeviceType4",
+];
+
+// Find the M-Bus devices connected to the meter, returns (channel, device type) for every
+// channel (1-4) that reports a valid device type (e.g. 3 for gas, 7 for water)
+async fn parse_mbus_devices(obis: &ObisValues<'_>) -> Vec<(usize, i64)> {
+    let mut devices = Vec::new();
+    for channel in 1..=4 {
+        let id = format!("0-{}:24.1.0", channel);
+        if let Ok(_values) = obis.get(&id) {
+            if let Some(Ok(_device_type)) = _values.first().map(|x| x.parse::<i64>()) {
+                devices.push((channel, _device_type));
+            }
+        }
+    }
+    devices
+}
+
+// The M-Bus devices of a telegram and the reading of its gas meter, read once per telegram and
+// passed to everything that needs them
+struct MbusReadings {
+    // (channel, device type) of the M-Bus devices connected to the meter
+    devices: Vec<(usize, i64)>,
+    // Gas reading as (time the gas meter was read, reading, unit)
+    gas: Result<(Option<i64>, f64, String), ObisError>,
+}
+
+impl MbusReadings {
+    async fn parse(obis: &ObisValues<'_>) -> MbusReadings {
+        let devices = parse_mbus_devices(obis).await;
+        verbose!("M-Bus devices: {:?}", devices);
+        MbusReadings {
+            gas: parse_gas_usage_accumulative(obis, gas_channel(&devices)).await,
+            devices,
+        }
+    }
+
+    // Whether the telegram reports a gas meter, its reading should be in the telegram then
+    fn has_gas_meter(&self) -> bool {
+        self.devices
+            .iter()
+            .any(|(_, _device_type)| *_device_type == MBUS_DEVICE_TYPE_GAS)
+    }
+}
+
+// M-Bus device type of gas meters
+const MBUS_DEVICE_TYPE_GAS: i64 = 3;
+
+// Unit gas meters report their reading in
+pub const GAS_UNIT: &str = "m3";
+
+// Tag the measurements of a telegram with the unit they were reported in and the device type of
+// the M-Bus device they were read from
+fn set_tags(
+    telegram: &mut Telegram,
+    gas_unit: Option<&str>,
+    limiter_unit: Option<&str>,
+    mbus_devices: &[(usize, i64)],
+) {
+    if let Some(_gas_unit) = gas_unit {
+        telegram.set_unit("gasUsageAccumulative", _gas_unit);
+    }
+    if let Some(_limiter_unit) = limiter_unit {
+        telegram.set_unit("limiterThreshold", _limiter_unit);
+    }
+    set_device_types(telegram, mbus_devices);
+}
+
+// Tag the measurements read from an M-Bus device with the device type of its channel
+fn set_device_types(telegram: &mut Telegram, mbus_devices: &[(usize, i64)]) {
+    let channel = gas_channel(mbus_devices);
+    if let Some((_, _device_type)) = mbus_devices.iter().find(|(_c, _)| *_c == channel) {
+        for name in GAS_CHANNEL_MEASUREMENTS.iter() {
+            telegram.set_device_type(name, *_device_type);
+        }
+    }
+}
+
+// M-Bus channel the gas meter is connected to, channel 1 when no device reports to be one
+fn gas_channel(mbus_devices: &[(usize, i64)]) -> usize {
+    mbus_devices
+        .iter()
+        .find(|(_, _device_type)| *_device_type == MBUS_DEVICE_TYPE_GAS)
+        .map(|(_channel, _)| *_channel)
+        .unwrap_or(1)
+}
+
+// Parse the valve state of the gas meter (e.g. 0 closed, 1 open), read from the M-Bus channel
+// the gas meter is connected to
+async fn parse_gas_valve_state(
+    obis: &ObisValues<'_>,
+    mbus_devices: &[(usize, i64)],
+) -> Result<i64, ObisError> {
+    let channel = gas_channel(mbus_devices);
+    let id = format!("0-{}:24.4.0", channel);
+    let values = obis.get(&id)?;
+    match values.first() {
+        Some(_value) => _value
+            .parse::<i64>()
+            .map_err(|_| ObisError::Invalid("Could not parse gas valve state")),
+        None => Err(ObisError::NoValues),
+    }
+}
+
+// Parse current gas accumulative usage from the M-Bus channel of the gas meter, together with the
+// time the gas meter was read. Some DSMR 4 meters send an extra 0-1:24.2.1 line with the usage of the last hour slot next to the
+// total (see `fixtures/dsmr4-gas-slot.txt`), posting the slot would turn the graph into a
+// sawtooth. The total is never below the usage of a slot, so the highest reading is taken.
+pub async fn parse_gas_usage_accumulative(
+    obis: &ObisValues<'_>,
+    channel: usize,
+) -> Result<(Option<i64>, f64, String), ObisError> {
+    let mut lines = obis.get_all(&format!("0-{}:24.2.1", channel));
+    // Belgian meters report the gas reading as 0-n:24.2.3
+    if lines.is_empty() {
+        lines = obis.get_all(&format!("0-{}:24.2.3", channel));
+    }
+    // DSMR 2.2 and 3.0 meters use a different format
+    if lines.is_empty() {
+        return parse_gas_usage_accumulative_dsmr3(obis, channel);
+    }
+
+    let mut readings = Vec::new();
+    for values in lines {
+        readings.push(parse_gas_line(values)?);
+    }
+    if readings.len() > 1 {
+        verbose!("Multiple gas readings, taking the highest: {:?}", readings);
+    }
+    readings
+        .into_iter()
+        .max_by(|(_, a, _), (_, b, _)| a.total_cmp(b))
+        .ok_or(ObisError::NoValues)
+}
+
+// Parse the values of a gas reading line, the time the gas meter was read and the reading
+fn parse_gas_line(values: &[&str]) -> Result<(Option<i64>, f64, String), &'static str> {
+    let timestamp = values
+        .first()
+        .and_then(|_timestamp| parse_dsmr_timestamp(_timestamp).ok());
+    let value = values.get(1);
+
+    match value {
+        Some(_value) => {
+            // The unit is normally m3, but other M-Bus devices on the channel (e.g. a heat
+            // meter) report theirs, e.g. `(00123.456*GJ)`
+            let (_value, _unit) = match split_unit(_value)? {
+                (_, "") => return Err("Invalid gas usage detected, not parsing"),
+                _parts => _parts,
+            };
+            // Some meters report integer m3
+            let _value_parsed = _value.parse::<f64>();
+            if _value_parsed.is_err() {
+                return Err("Could not parse gas usage accumulative");
+            }
+            Ok(gas_reading(timestamp, _value_parsed.unwrap(), _unit))
+        }
+        None => Err("Could not read gas usage accumulative"),
+    }
+}
+
+// Parse current gas accumulative usage of DSMR 2.2 and 3.0 meters. The reading is on the line
+// after the header (or at the end of the header on some meters), the header has the unit (m3 or
+// dm3) as its last value, e.g.:
+//
+//   0-1:24.3.0(121221170000)(00)(60)(1)(0-1:24.2.1)(m3)
+//   (04312.372)
+fn parse_gas_usage_accumulative_dsmr3(
+    obis: &ObisValues<'_>,
+    channel: usize,
+) -> Result<(Option<i64>, f64, String), ObisError> {
+    let values = obis.get(&format!("0-{}:24.3.0", channel))?;
+    if values.len() < 7 {
+        return Err(ObisError::NoValues);
+    }
+
+    let timestamp = parse_dsmr_timestamp(values[0]).ok();
+    let (value, _) = split_unit(values[6])?;
+    let value = value
+        .parse::<f64>()
+        .map_err(|_| "Could not parse gas usage accumulative")?;
+    Ok(gas_reading(timestamp, value, values[5]))
+}
+
+// Unit of the limiter threshold, kW on DSMR 5 meters but A on some others. Both end up in the
+// same series, so the unit is tagged.
+fn parse_limiter_threshold_unit(obis: &ObisValues<'_>) -> Result<String, &'static str> {
+    let values = obis.get("0-0:17.0.0")?;
+    let value = values.last().ok_or("Could not read limiter threshold")?;
+    match split_unit(value)? {
+        (_, "") => Err("Limiter threshold without a unit"),
+        (_, _unit) => Ok(_unit.to_string()),
+    }
+}
+
+// A gas reading in the unit the meter reported it in, dm3 is converted to m3
+fn gas_reading(timestamp: Option<i64>, value: f64, unit: &str) -> (Option<i64>, f64, String) {
+    match unit {
+        "dm3" => (timestamp, value / 1000.0, GAS_UNIT.to_string()),
+        _ => (timestamp, value, unit.to_string()),
+    }
+}
+
+// Parse a telegram into the measurements the adapter posts, stamped with the time of the telegram
+// and tagged with their unit and M-Bus device type. Measurements that need earlier telegrams
+// (e.g. gasFlowRate or the `--post-deltas` deltas) are left out.
+pub async fn parse(config: &Config, stats: &mut Stats, obis: &ObisValues<'_>) -> Telegram {
+    let mbus = MbusReadings::parse(obis).await;
+    let measurements = parse_telegram(config, stats, obis, &mbus).await;
+    let mut telegram = Telegram::new(measurements, parse_timestamp(obis).await.ok());
+    telegram.gas_timestamp = mbus
+        .gas
+        .as_ref()
+        .ok()
+        .and_then(|(_gas_timestamp, _, _)| *_gas_timestamp);
+    let gas_unit = mbus
+        .gas
+        .as_ref()
+        .ok()
+        .map(|(_, _, _gas_unit)| _gas_unit.as_str());
+    let limiter_unit = parse_limiter_threshold_unit(obis).ok();
+    set_tags(
+        &mut telegram,
+        gas_unit,
+        limiter_unit.as_deref(),
+        &mbus.devices,
+    );
+    telegram
+}
+
+async fn parse_telegram(
+    config: &Config,
+    stats: &mut Stats,
+    obis: &ObisValues<'_>,
+    mbus: &MbusReadings,
+) -> Vec<(&'static str, FieldValue)> {
+    let mut measurements = Vec::new();
+    // OBIS fields the meter should have sent and how many of those could be parsed, optional
+    // fields only count when they are present. Calculated measurements don't count.
+    let mut fields_expected = 0;
+    let mut fields_parsed = 0;
+
+    for definition in obis_map(config.meter_profile) {
+        let required = match definition.transform {
+            ObisTransform::Gas => mbus.has_gas_meter(),
+            _ => definition.required,
+        };
+        match parse_obis(definition, obis, mbus).await {
+            Ok(_value) => {
+                verbose!("{}: {:?}", definition.name, _value);
+                measurements.push((definition.name, _value));
+                fields_expected += 1;
+                fields_parsed += 1;
+            }
+            Err(ObisError::Missing) if !required => {}
+            Err(_err) => {
+                fields_expected += 1;
+                stats.parse_error(definition.name);
+                log!("Error: could not find {} {}", definition.name, _err);
+            }
+        }
+    }
+
+    // Calculate nett usage, only possible when both usage and production were parsed
+    let w_usage = float_measurement(&measurements, "wattUsage");
+    let w_production = float_measurement(&measurements, "wattProduction");
+    match (w_production, w_usage) {
+        (Some(_w_production), Some(_w_usage)) => {
+            if !config.no_nett {
+                let _w_nett = config.nett_sign.apply(_w_production, _w_usage);
+                verbose!("Watt nett ({}): {:?}", config.nett_sign, _w_nett);
+                measurements.push(("wattNett", FieldValue::Float(_w_nett)));
+            }
+
+            // Same as wattNett with usage-minus-production, but independent of --nett-sign
+            let _grid_power = _w_usage - _w_production + 0.0;
+            verbose!("Grid power: {:?}", _grid_power);
+            measurements.push(("gridPower", FieldValue::Float(_grid_power)));
+        }
+        _ => log!("Error: could not calculate Watt nett, usage or production missing"),
+    }
+
+    // Calculate nett accumulative usage, only possible when both usage and production were parsed
+    let w_usage_accumulative = float_measurement(&measurements, "wattUsageAccumulative");
+    let w_production_accumulative = float_measurement(&measurements, "wattProductionAccumulative");
+    match (w_production_accumulative, w_usage_accumulative) {
+        _ if config.no_nett => {}
+        (Some(_w_production_accumulative), Some(_w_usage_accumulative)) => {
+            let _w_accumulative_nett = round_kwh(
+                config
+                    .nett_sign
+                    .apply(_w_production_accumulative, _w_usage_accumulative),
+            );
+            verbose!(
+                "Watt accumulative nett ({}): {:?}",
+                config.nett_sign,
+                _w_accumulative_nett
+            );
+            measurements.push((
+                "wattAccumulativeNett",
+                FieldValue::Float(_w_accumulative_nett),
+            ));
+        }
+        _ => log!("Error: could not calculate Watt accumulative nett, usage or production missing"),
+    }
+
+    // Import positive and export negative in a single series, for power flow dashboards
+    if config.combined_power {
+        if let (Some(_w_usage), Some(_w_production)) = (w_usage, w_production) {
+            let _power_flow = _w_usage - _w_production + 0.0;
+            verbose!("Power flow: {:?}", _power_flow);
+            measurements.push(("powerFlow", FieldValue::Float(_power_flow)));
+        }
+        if let (Some(_w_usage_accumulative), Some(_w_production_accumulative)) =
+            (w_usage_accumulative, w_production_accumulative)
+        {
+            let _power_flow_accumulative =
+                round_kwh(_w_usage_accumulative - _w_production_accumulative);
+            verbose!("Power flow accumulative: {:?}", _power_flow_accumulative);
+            measurements.push((
+                "powerFlowAccumulative",
+                FieldValue::Float(_power_flow_accumulative),
+            ));
+        }
+    }
+
+    // Costs are only calculated when the prices are configured
+    if let (Some(_price_tariff_1), Some(_price_tariff_2)) =
+        (config.price_tariff_1, config.price_tariff_2)
+    {
+        match calculate_cost_electricity(obis, _price_tariff_1, _price_tariff_2).await {
+            Ok(_cost_electricity) => {
+                // Round to cents
+                let _cost_electricity = (_cost_electricity * 100.0).round() / 100.0;
+                verbose!("Cost electricity: {:?}", _cost_electricity);
+                measurements.push(("costElectricity", FieldValue::Float(_cost_electricity)));
+            }
+            Err(_err) => {
+                stats.parse_error("costElectricity");
+                log!("Error: could not calculate electricity cost {}", _err);
+            }
+        }
+    }
+    let gas_usage = float_measurement(&measurements, "gasUsageAccumulative");
+    if let (Some(_price_gas), Some(_gas_usage)) = (config.price_gas, gas_usage) {
+        let _cost_gas = (_gas_usage * _price_gas * 100.0).round() / 100.0;
+        verbose!("Cost gas: {:?}", _cost_gas);
+        measurements.push(("costGas", FieldValue::Float(_cost_gas)));
+    }
+
+    let gas_valve_state = parse_gas_valve_state(obis, &mbus.devices).await;
+    match gas_valve_state {
+        Ok(_gas_valve_state) => {
+            fields_expected += 1;
+            fields_parsed += 1;
+            verbose!("Gas valve state: {:?}", _gas_valve_state);
+            measurements.push(("gasValveState", FieldValue::Int(_gas_valve_state)));
+        }
+        // Most gas meters don't have a valve
+        Err(ObisError::Missing) => {}
+        Err(_err) => {
+            fields_expected += 1;
+            stats.parse_error("gasValveState");
+            log!("Error: could not find gas valve state {}", _err);
+        }
+    }
+
+    let power_failure_log = parse_power_failure_log(obis).await;
+    match power_failure_log {
+        Ok((_failure_event_count, _entries)) => {
+            let _last_failure_duration = _entries
+                .iter()
+                .max_by_key(|(_end_time, _)| *_end_time)
+                .map(|(_, _duration)| *_duration);
+            fields_expected += 1;
+            fields_parsed += 1;
+            verbose!(
+                "Power failures: {:?}, last duration: {:?}",
+                _failure_event_count,
+                _last_failure_duration
+            );
+            measurements.push(("failureEventCount", FieldValue::Int(_failure_event_count)));
+            if let Some(_last_failure_duration) = _last_failure_duration {
+                measurements.push((
+                    "lastFailureDuration",
+                    FieldValue::Int(_last_failure_duration),
+                ));
+            }
+        }
+        // Only DSMR 4 and 5 meters keep a power failure log
+        Err(ObisError::Missing) => {}
+        Err(_err) => {
+            fields_expected += 1;
+            stats.parse_error("failureEventCount");
+            log!("Error: could not find power failure log {}", _err);
+        }
+    }
+
+    // Nothing could be parsed, this is not a valid telegram
+    if measurements.is_empty() {
+        return measurements;
+    }
+
+    measurements.push((
+        "mbusDeviceCount",
+        FieldValue::Int(mbus.devices.len() as i64),
+    ));
+    for (_channel, _device_type) in &mbus.devices {
+        measurements.push((
+            MBUS_DEVICE_TYPE_MEASUREMENTS[_channel - 1],
+            FieldValue::Int(*_device_type),
+        ));
+    }
+
+    // At least one field was parsed, so this never divides by zero
+    let parse_completeness =
+        (fields_parsed as f64 * 1000.0 / fields_expected as f64).round() / 10.0;
+    verbose!("Parse completeness: {:?}%", parse_completeness);
+    measurements.push(("parseCompleteness", FieldValue::Float(parse_completeness)));
+
+    // Mark that the adapter is alive, so gaps in the data can be told apart from zero usage
+    measurements.push(("adapterHeartbeat", FieldValue::Int(1)));
+
+    measurements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::influx::line_protocol;
+    use crate::telegram::Measurement;
+    use futures::executor::block_on;
+
+    // Parse a telegram with the default config
+    fn parse(telegram: &str) -> Vec<(&'static str, FieldValue)> {
+        parse_with(&Config::default(), telegram)
+    }
+
+    fn parse_with(config: &Config, telegram: &str) -> Vec<(&'static str, FieldValue)> {
+        let obis = ObisValues::parse(telegram);
+        let mbus = block_on(MbusReadings::parse(&obis));
+        block_on(parse_telegram(config, &mut Stats::default(), &obis, &mbus))
+    }
+
+    // Parse a single value of the OBIS id the measurement `name` is read from
+    fn parse_obis_value(name: &str, value: &str) -> Result<FieldValue, ObisError> {
+        let definition = OBIS_MAP
+            .iter()
+            .find(|_definition| _definition.name == name)
+            .unwrap();
+        let line = format!("{}({})", definition.id, value);
+        let obis = ObisValues::parse(&line);
+        block_on(parse_obis(
+            definition,
+            &obis,
+            &block_on(MbusReadings::parse(&obis)),
+        ))
+    }
+
+    fn fixture(name: &str) -> String {
+        std::fs::read_to_string(format!("fixtures/{}", name)).unwrap()
+    }
+
+    fn measurement(measurements: &[(&'static str, FieldValue)], name: &str) -> Option<FieldValue> {
+        measurements
+            .iter()
+            .find(|(_name, _)| *_name == name)
+            .map(|(_, _value)| _value.clone())
+    }
+
+    #[test]
+    fn split_unit_takes_a_comma_as_decimal_separator() {
+        assert_eq!(split_unit("02,351*kW"), Ok((Cow::from("02.351"), "kW")));
+        assert_eq!(split_unit("02.351*kW"), Ok((Cow::from("02.351"), "kW")));
+        assert!(split_unit("02,351.1*kW").is_err());
+    }
+
+    #[test]
+    fn split_unit_rejects_several_asterisks() {
+        // A garbled read, splitting on the last `*` leaves `123*456` which isn't a number
+        assert_eq!(split_unit("123*456*kWh"), Err("Invalid number"));
+        assert_eq!(split_unit("*0.5*kW"), Err("Invalid number"));
+        assert!(parse_obis_value("wattUsageAccumulative", "123*456*kWh").is_err());
+    }
+
+    #[test]
+    fn parses_comma_decimals_without_touching_text() {
+        let telegram = "/FLU5\\253769484_A\r\n\r\n\
+            0-0:96.1.4(50217)\r\n\
+            1-0:1.8.1(000123,034*kWh)\r\n\
+            1-0:1.8.2(000015,758*kWh)\r\n\
+            0-0:96.14.0(0001)\r\n\
+            1-0:1.7.0(01,250*kW)\r\n\
+            0-0:96.13.0(Onderhoud, 10:00-12:00)\r\n\
+            !0000\r\n";
+        let measurements = parse(telegram);
+        assert_eq!(
+            measurement(&measurements, "wattUsage"),
+            Some(FieldValue::Float(1250.0))
+        );
+        assert_eq!(
+            measurement(&measurements, "wattUsageAccumulative"),
+            Some(FieldValue::Float(138.792))
+        );
+        // Only the numbers are normalized, the text message keeps its comma
+        assert_eq!(
+            ObisValues::parse(telegram).get("0-0:96.13.0"),
+            Ok(&["Onderhoud, 10:00-12:00"][..])
+        );
+    }
+
+    #[test]
+    fn parse_obis_takes_numbers_without_zero_padding() {
+        assert_eq!(
+            parse_obis_value("wattUsage", ".131*kW"),
+            Ok(FieldValue::Float(131.0))
+        );
+        assert_eq!(
+            parse_obis_value("wattUsage", "0*kW"),
+            Ok(FieldValue::Float(0.0))
+        );
+        assert_eq!(
+            parse_obis_value("wattUsage", "00.000*kW"),
+            Ok(FieldValue::Float(0.0))
+        );
+        assert_eq!(
+            parse_obis_value("wattProduction", "0001.727*kW"),
+            Ok(FieldValue::Float(1727.0))
+        );
+    }
+
+    #[test]
+    fn parse_obis_keeps_the_sign_of_signed_values() {
+        assert_eq!(
+            parse_obis_value("wattUsage", "-01.250*kW"),
+            Ok(FieldValue::Float(-1250.0))
+        );
+        assert_eq!(
+            parse_obis_value("wattUsage", "-00.131*kW"),
+            Ok(FieldValue::Float(-131.0))
+        );
+        assert!(parse_obis_value("wattUsage", "--01.250*kW").is_err());
+
+        // Usage without production is a negative nett power, production minus usage
+        let measurements = parse(include_str!("../fixtures/dsmr42.txt"));
+        assert_eq!(
+            measurement(&measurements, "wattNett"),
+            Some(FieldValue::Float(-131.0))
+        );
+    }
+
+    #[test]
+    fn parses_dsmr3_telegrams() {
+        let measurements = parse(include_str!("../fixtures/dsmr3.txt"));
+        assert_eq!(
+            measurement(&measurements, "wattUsage"),
+            Some(FieldValue::Float(450.0))
+        );
+        assert_eq!(
+            measurement(&measurements, "wattUsageAccumulative"),
+            Some(FieldValue::Float(580.245))
+        );
+        // The gas reading is on the line after its id
+        assert_eq!(
+            measurement(&measurements, "gasUsageAccumulative"),
+            Some(FieldValue::Float(4312.372))
+        );
+    }
+
+    #[test]
+    fn converts_dsmr3_gas_readings_in_dm3() {
+        let telegram = "/ISk5\\2ME382-1003\r\n\r\n\
+            0-1:24.3.0(121221170000)(00)(60)(1)(0-1:24.2.1)(dm3)(4312372)\r\n\
+            !\r\n";
+        let obis = ObisValues::parse(telegram);
+        let (_, gas_usage, unit) = block_on(parse_gas_usage_accumulative(&obis, 1)).unwrap();
+        assert_eq!(gas_usage, 4312.372);
+        assert_eq!(unit, "m3");
+    }
+
+    #[test]
+    fn parses_nordic_han_telegrams() {
+        let config = Config {
+            meter_profile: MeterProfile::Nordic,
+            ..Config::default()
+        };
+        let measurements = parse_with(&config, include_str!("../fixtures/han-sweden.txt"));
+        let expected = [
+            ("wattUsage", 1727.0),
+            ("wattUsageAccumulative", 6678.394),
+            ("wattProduction", 0.0),
+            ("wattProductionAccumulative", 0.0),
+            ("reactivePowerImport", 0.0),
+            ("reactivePowerExport", 309.0),
+            ("wattNett", -1727.0),
+        ];
+        for (name, value) in &expected {
+            assert_eq!(
+                measurement(&measurements, name),
+                Some(FieldValue::Float(*value)),
+                "{}",
+                name
+            );
+        }
+        // Only the energy of both tariffs together
+        assert_eq!(
+            measurement(&measurements, "wattUsageAccumulativeTariff1"),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_nordic_power_in_w() {
+        let config = Config {
+            meter_profile: MeterProfile::Nordic,
+            ..Config::default()
+        };
+        let telegram = "/ELL5\\253833635_A\r\n\r\n\
+            1-0:1.8.0(6678394*Wh)\r\n\
+            1-0:1.7.0(1727*W)\r\n\
+            !0000\r\n";
+        let measurements = parse_with(&config, telegram);
+        assert_eq!(
+            measurement(&measurements, "wattUsage"),
+            Some(FieldValue::Float(1727.0))
+        );
+        assert_eq!(
+            measurement(&measurements, "wattUsageAccumulative"),
+            Some(FieldValue::Float(6678.394))
+        );
+    }
+
+    #[test]
+    fn takes_the_gas_total_instead_of_the_hour_slot() {
+        // The hour slot comes before the total in the fixture
+        let measurements = parse(&fixture("dsmr4-gas-slot.txt"));
+        assert_eq!(
+            measurement(&measurements, "gasUsageAccumulative"),
+            Some(FieldValue::Float(7025.512))
+        );
+
+        let obis = ObisValues::parse(
+            "0-1:24.2.1(210205130000W)(07025.512*m3)\r\n\
+            0-1:24.2.1(210205130000W)(00000.153*m3)\r\n",
+        );
+        assert_eq!(
+            block_on(parse_gas_usage_accumulative(&obis, 1)),
+            Ok((Some(1612526400), 7025.512, "m3".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_gas_line_takes_comma_decimals_and_integers() {
+        assert_eq!(
+            parse_gas_line(&["210205130000W", "07025,512*m3"]),
+            Ok((Some(1612526400), 7025.512, "m3".to_string()))
+        );
+        assert_eq!(
+            parse_gas_line(&["210205130000W", "07025*m3"]),
+            Ok((Some(1612526400), 7025.0, "m3".to_string()))
+        );
+
+        let obis = ObisValues::parse("0-1:24.2.1(210205130000W)(07025,512*m3)\r\n");
+        assert_eq!(
+            block_on(parse_gas_usage_accumulative(&obis, 1)),
+            Ok((Some(1612526400), 7025.512, "m3".to_string()))
+        );
+    }
+
+    #[test]
+    fn expects_a_gas_reading_only_from_meters_with_a_gas_meter() {
+        let config = Config {
+            price_tariff_1: Some(0.2),
+            price_tariff_2: Some(0.2),
+            ..Config::default()
+        };
+        let telegram = fixture("dsmr42.txt");
+        let without_gas_meter = telegram
+            .replace("0-1:24.1.0(003)\r\n", "")
+            .replace("0-1:24.2.1(210205130000W)(07025.512*m3)\r\n", "");
+        let measurements = parse_with(&config, &without_gas_meter);
+        assert!(measurement(&measurements, "costElectricity").is_some());
+        assert_eq!(
+            measurement(&measurements, "parseCompleteness"),
+            Some(FieldValue::Float(100.0))
+        );
+
+        // The gas meter is there, but its reading isn't
+        let without_gas_reading =
+            telegram.replace("0-1:24.2.1(210205130000W)(07025.512*m3)\r\n", "");
+        let mut stats = Stats::default();
+        let obis = ObisValues::parse(&without_gas_reading);
+        let mbus = block_on(MbusReadings::parse(&obis));
+        let measurements = block_on(parse_telegram(&config, &mut stats, &obis, &mbus));
+        assert!(matches!(
+            measurement(&measurements, "parseCompleteness"),
+            Some(FieldValue::Float(_completeness)) if _completeness < 100.0
+        ));
+        assert_eq!(stats.parse_errors.get("gasUsageAccumulative"), Some(&1));
+    }
+
+    #[test]
+    fn tells_a_missing_obis_id_apart_from_an_invalid_value() {
+        let devices = [(1, 3)];
+        let obis = ObisValues::parse("0-1:24.4.0()\r\n");
+        assert_eq!(obis.get("0-0:96.7.21"), Err(ObisError::Missing));
+        assert_eq!(obis.get("0-1:24.4.0"), Err(ObisError::NoValues));
+        assert_eq!(
+            block_on(parse_gas_valve_state(&obis, &devices)),
+            Err(ObisError::NoValues)
+        );
+        assert_eq!(
+            block_on(parse_power_failure_log(&obis)),
+            Err(ObisError::Missing)
+        );
+
+        let obis = ObisValues::parse("0-1:24.4.0(open)\r\n");
+        assert_eq!(
+            block_on(parse_gas_valve_state(&obis, &devices)),
+            Err(ObisError::Invalid("Could not parse gas valve state"))
+        );
+        let obis = ObisValues::parse("");
+        assert_eq!(
+            block_on(parse_gas_valve_state(&obis, &devices)),
+            Err(ObisError::Missing)
+        );
+    }
+
+    #[test]
+    fn posts_an_all_zero_telegram_as_zeros() {
+        let telegram = "/KFM5KAIFA-METER\r\n\r\n\
+            1-3:0.2.8(42)\r\n\
+            0-0:1.0.0(210212094443W)\r\n\
+            1-0:1.8.1(000000.000*kWh)\r\n\
+            1-0:1.8.2(000000.000*kWh)\r\n\
+            1-0:2.8.1(000000.000*kWh)\r\n\
+            1-0:2.8.2(000000.000*kWh)\r\n\
+            0-0:96.14.0(0001)\r\n\
+            1-0:1.7.0(00.000*kW)\r\n\
+            1-0:2.7.0(-00.000*kW)\r\n\
+            0-1:24.2.1(210212094000W)(00000.000*m3)\r\n\
+            !0000\r\n";
+        let measurements = parse(telegram);
+        let zeros: Vec<(&'static str, FieldValue)> = [
+            "wattUsage",
+            "wattUsageAccumulative",
+            "wattProduction",
+            "wattProductionAccumulative",
+            "gasUsageAccumulative",
+            "wattNett",
+            "wattAccumulativeNett",
+        ]
+        .iter()
+        .map(|_name| (*_name, measurement(&measurements, _name).unwrap()))
+        .collect();
+
+        let config = Config {
+            host: "test".to_string(),
+            ..Config::default()
+        };
+        // A `-00.000*kW` reading is posted as 0 as well, not as `-0`
+        let lines = line_protocol(&config, &Telegram::new(zeros, Some(1613119483)));
+        assert_eq!(lines.len(), 7);
+        for _line in lines {
+            assert!(
+                _line.ends_with(",host=test value=0 1613119483"),
+                "{}",
+                _line
+            );
+        }
+    }
+
+    #[test]
+    fn posts_the_quarter_hour_average_once_next_to_the_monthly_peak() {
+        let telegram = fixture("fluvius.txt");
+        let measurements = parse(&telegram);
+        assert_eq!(
+            measurement(&measurements, "currentQuarterAverage"),
+            Some(FieldValue::Float(2351.0))
+        );
+        assert_eq!(
+            measurements
+                .iter()
+                .filter(|(_, _value)| *_value == FieldValue::Float(2351.0))
+                .count(),
+            1
+        );
+        assert_eq!(
+            measurement(&measurements, "monthlyPeakPower"),
+            Some(FieldValue::Float(2589.0))
+        );
+    }
+
+    #[test]
+    fn gas_readings_round_trip_exactly() {
+        let (_, gas_usage, _) = parse_gas_line(&["210205130000W", "07025.512*m3"]).unwrap();
+        assert_eq!(gas_usage.to_string(), "7025.512");
+
+        let config = Config {
+            host: "test".to_string(),
+            ..Config::default()
+        };
+        let telegram = Telegram::new(
+            vec![("gasUsageAccumulative", FieldValue::Float(gas_usage))],
+            Some(1612526400),
+        );
+        assert_eq!(
+            line_protocol(&config, &telegram),
+            ["gasUsageAccumulative,host=test value=7025.512 1612526400"]
+        );
+    }
+
+    #[test]
+    fn reads_the_gas_meter_from_its_m_bus_channel() {
+        // A water meter on channel 1, the gas meter on channel 2
+        let obis = ObisValues::parse(
+            "/KFM5KAIFA-METER\r\n\r\n\
+            0-0:1.0.0(210205130000W)\r\n\
+            1-0:1.7.0(00.131*kW)\r\n\
+            0-1:24.1.0(007)\r\n\
+            0-1:24.2.1(210205130000W)(00012.500*m3)\r\n\
+            0-2:24.1.0(003)\r\n\
+            0-2:24.2.1(210205130000W)(07025.512*m3)\r\n\
+            0-2:24.4.0(1)\r\n\
+            !\r\n",
+        );
+        let telegram = block_on(super::parse(
+            &Config::default(),
+            &mut Stats::default(),
+            &obis,
+        ));
+        let measurement = |_name| {
+            telegram
+                .get(_name)
+                .map(|_measurement| (_measurement.value, _measurement.device_type))
+        };
+        assert_eq!(
+            measurement("gasUsageAccumulative"),
+            Some((FieldValue::Float(7025.512), Some(3)))
+        );
+        assert_eq!(
+            measurement("gasValveState"),
+            Some((FieldValue::Int(1), Some(3)))
+        );
+        assert_eq!(
+            measurement("mbusDeviceCount"),
+            Some((FieldValue::Int(2), None))
+        );
+        assert_eq!(
+            measurement("mbusDeviceType1"),
+            Some((FieldValue::Int(7), None))
+        );
+        assert_eq!(
+            measurement("mbusDeviceType2"),
+            Some((FieldValue::Int(3), None))
+        );
+        assert_eq!(measurement("mbusDeviceType3"), None);
+    }
+
+    #[test]
+    fn tags_m_bus_measurements_with_the_device_type_of_their_channel() {
+        let mut config = Config {
+            host: "test".to_string(),
+            ..Config::default()
+        };
+        // A water meter on channel 1, the gas meter on channel 2
+        let mbus_devices = [(1, 7), (2, 3)];
+        let mut telegram = Telegram::new(
+            vec![
+                ("wattUsage", FieldValue::Float(131.0)),
+                ("gasUsageAccumulative", FieldValue::Float(7025.512)),
+                ("gasValveState", FieldValue::Int(1)),
+                ("mbusDeviceType1", FieldValue::Int(7)),
+                ("mbusDeviceType2", FieldValue::Int(3)),
+            ],
+            Some(1612526400),
+        );
+        set_device_types(&mut telegram, &mbus_devices);
+        assert_eq!(
+            line_protocol(&config, &telegram),
+            [
+                "wattUsage,host=test value=131 1612526400",
+                "gasUsageAccumulative,host=test,device_type=3 value=7025.512 1612526400",
+                "gasValveState,host=test,device_type=3 value=1i 1612526400",
+                "mbusDeviceType1,host=test value=7i 1612526400",
+                "mbusDeviceType2,host=test value=3i 1612526400",
+            ]
+        );
+
+        config.single_measurement = Some("p1".to_string());
+        assert_eq!(
+            line_protocol(&config, &telegram),
+            [
+                "p1,host=test,gasUsageAccumulativeDeviceType=3,gasValveStateDeviceType=3 \
+                wattUsage=131,gasUsageAccumulative=7025.512,gasValveState=1i,mbusDeviceType1=7i,\
+                mbusDeviceType2=3i 1612526400"
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_returns_the_measurements_with_their_time_and_tags() {
+        let obis_values = fixture("dsmr42.txt");
+        let obis = ObisValues::parse(&obis_values);
+        let telegram = block_on(super::parse(
+            &Config::default(),
+            &mut Stats::default(),
+            &obis,
+        ));
+        assert_eq!(telegram.timestamp, Some(1613119483));
+        assert_eq!(
+            telegram.get("gasUsageAccumulative"),
+            Some(Measurement {
+                name: "gasUsageAccumulative",
+                value: FieldValue::Float(7025.512),
+                timestamp: Some(1613119483),
+                unit: Some("m3".to_string()),
+                device_type: Some(3),
+            })
+        );
+        assert!(telegram
+            .measurements()
+            .all(|_measurement| _measurement.timestamp == telegram.timestamp));
+    }
+
+    #[test]
+    fn tags_the_limiter_threshold_with_its_unit() {
+        for (_value, _unit) in &[("999.9*kW", "kW"), ("016*A", "A")] {
+            let line = format!("0-0:17.0.0({})", _value);
+            let obis = ObisValues::parse(&line);
+            assert_eq!(parse_limiter_threshold_unit(&obis), Ok(_unit.to_string()));
+        }
+        let obis = ObisValues::parse("0-0:17.0.0(999.9)");
+        assert!(parse_limiter_threshold_unit(&obis).is_err());
+
+        let telegram = block_on(super::parse(
+            &Config::default(),
+            &mut Stats::default(),
+            &ObisValues::parse(&fixture("fluvius.txt")),
+        ));
+        assert_eq!(
+            telegram.get("limiterThreshold").unwrap().unit,
+            Some("kW".to_string())
+        );
+    }
+}
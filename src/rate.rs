@@ -0,0 +1,65 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Bytes and telegrams read from the meter within a time window, a DSMR5.0 meter sends about one
+// telegram every second so a much lower rate points at a framing or baud rate problem
+pub struct ReadRate {
+    bytes: u64,
+    telegrams: u64,
+    since: Instant,
+}
+
+impl ReadRate {
+    pub fn new() -> ReadRate {
+        ReadRate {
+            bytes: 0,
+            telegrams: 0,
+            since: Instant::now(),
+        }
+    }
+
+    pub fn add_bytes(&mut self, count: usize) {
+        self.bytes += count as u64;
+    }
+
+    pub fn add_telegram(&mut self) {
+        self.telegrams += 1;
+    }
+
+    // Time the current window started
+    pub fn since(&self) -> Instant {
+        self.since
+    }
+
+    // Start a new window, returns the rate over the window that ended
+    pub fn take(&mut self) -> Summary {
+        let summary = Summary {
+            bytes: self.bytes,
+            telegrams: self.telegrams,
+            elapsed: self.since.elapsed(),
+        };
+        *self = ReadRate::new();
+        summary
+    }
+}
+
+pub struct Summary {
+    bytes: u64,
+    telegrams: u64,
+    elapsed: Duration,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        write!(
+            f,
+            "{:.0} bytes/s, {:.1} telegrams/min ({} bytes and {} telegrams in {} seconds)",
+            self.bytes as f64 / seconds,
+            self.telegrams as f64 * 60.0 / seconds,
+            self.bytes,
+            self.telegrams,
+            self.elapsed.as_secs()
+        )
+    }
+}
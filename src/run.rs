@@ -0,0 +1,555 @@
+use crate::adapter::Adapter;
+use crate::capture::Capture;
+use crate::config::{self, Config};
+use crate::framer::Framer;
+use crate::influx::{influx_write_url, ping_influx_db, read_certificate, TCP_KEEPALIVE};
+use crate::input::{self, Input};
+use crate::measurements::unknown_measurements;
+use crate::rate::ReadRate;
+use crate::state::State;
+use crate::{health, metrics, simulate, LOG_TO_STDERR, QUIET, VERBOSE};
+use futures::future;
+use mio::unix::UnixReady;
+use mio::{Events, Poll, Ready, Token};
+use mio_serial::SerialPort;
+use std::io;
+use std::io::Read;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+const INPUT_TOKEN: Token = Token(0);
+
+// Number of telegrams that can wait to be processed, about a minute worth on DSMR 5 meters
+const TELEGRAM_QUEUE_SIZE: usize = 60;
+
+// Resolves when the adapter is asked to stop, with Ctrl+C or SIGTERM (e.g. by systemd)
+async fn shutdown_signal() {
+    let mut terminate =
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(_terminate) => _terminate,
+            Err(_err) => {
+                log!("Warning: could not listen for SIGTERM: {}", _err);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+// Send a state notification to systemd, does nothing when not running as a systemd service
+#[cfg(feature = "systemd")]
+fn notify_systemd(state: sd_notify::NotifyState) {
+    if let Err(_err) = sd_notify::notify(false, &[state]) {
+        log!("Could not notify systemd: {}", _err);
+    }
+}
+
+// Log what to check when no valid telegram was received after startup
+fn log_startup_hints(config: &Config) {
+    log!(
+        "Warning: no valid telegram received within {} seconds after startup",
+        config.startup_timeout.as_secs()
+    );
+    match &config.tcp {
+        Some(_address) => {
+            log!("- Check that {} forwards the raw P1 data", _address);
+            log!("- Check the serial settings of the P1 reader (115200 8N1 for DSMR 4/5, 9600 7E1 for DSMR 2.2/3.0)");
+        }
+        None => {
+            log!(
+                "- Check that {} is the P1 cable (see --list-ports)",
+                config.tty
+            );
+            log!("- Check the serial settings, set --dsmr-version for meters older than DSMR 4");
+            log!("- Check that the data request line is powered, try --assert-rts");
+            log!("- Check the cable, some P1 cables need the data line inverted");
+        }
+    }
+    log!("- Run with --verbose to see what is received");
+    log!("Still waiting for telegrams");
+}
+
+// Raise the RTS and DTR lines, which drive the data request pin of the P1 port
+fn request_data(rx: &mut mio_serial::Serial) {
+    if let Err(_err) = rx.write_request_to_send(true) {
+        log!("Could not assert RTS: {}", _err);
+    }
+    if let Err(_err) = rx.write_data_terminal_ready(true) {
+        log!("Could not assert DTR: {}", _err);
+    }
+}
+
+fn ready_of_interest() -> Ready {
+    Ready::readable() | UnixReady::hup() | UnixReady::error()
+}
+
+fn is_closed(state: Ready) -> bool {
+    state.contains(UnixReady::hup() | UnixReady::error())
+}
+
+// Read the meter and post its telegrams until the input ends, `main` of the adapter
+pub async fn run() -> Result<(), reqwest::Error> {
+    // Log how long every telegram took, per step, when the closing spans show the busy time. Only
+    // the spans of the adapter, not those of e.g. the HTTP client.
+    #[cfg(feature = "trace")]
+    {
+        use tracing_subscriber::prelude::*;
+        tracing_subscriber::registry()
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+                    .with_writer(io::stderr),
+            )
+            .with(
+                tracing_subscriber::filter::Targets::new()
+                    .with_target(env!("CARGO_CRATE_NAME"), tracing::Level::DEBUG),
+            )
+            .init();
+    }
+
+    let mut config = match Config::load() {
+        Ok(_config) => _config,
+        Err(_err) => {
+            log!("Error: {}", _err);
+            process::exit(1);
+        }
+    };
+    if config.help {
+        print!("{}", config::usage());
+        return Ok(());
+    }
+    config.influx_urls = config
+        .influx_urls
+        .iter()
+        .map(|_influx_url| influx_write_url(_influx_url, config.influx_precision))
+        .collect();
+
+    if config.list_ports {
+        if let Err(_err) = input::list_ports() {
+            log!("Error: {}", _err);
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Keep stdout clean for the JSON output and the OBIS dump
+    if config.json || config.obis_dump {
+        LOG_TO_STDERR.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    VERBOSE.store(config.verbose, std::sync::atomic::Ordering::Relaxed);
+    QUIET.store(
+        config.quiet && !config.verbose,
+        std::sync::atomic::Ordering::Relaxed,
+    );
+
+    if !config.no_nett {
+        info!("Calculating nett power as {}", config.nett_sign);
+    }
+
+    for name in unknown_measurements(&config) {
+        log!("Warning: unknown measurement {}", name);
+    }
+
+    let last_telegram: health::LastTelegram = Arc::new(Mutex::new(None));
+    let metrics: metrics::Metrics = Arc::new(Mutex::new(String::new()));
+    if let Some(_health_port) = config.health_port {
+        tokio::spawn(health::serve(
+            _health_port,
+            config.health_max_age,
+            last_telegram.clone(),
+            metrics.clone(),
+        ));
+    }
+
+    if let Some(_interval) = config.interval {
+        info!(
+            "Posting measurements every {} seconds ({:?})",
+            _interval.as_secs(),
+            config.aggregate
+        );
+    }
+
+    // Create reqwest HTTP client, with a timeout so a hanging InfluxDB can't block reading. The
+    // connections to InfluxDB are kept open and reused, a new (TLS) connection for every post is
+    // expensive on e.g. a Pi Zero.
+    let mut client_builder = reqwest::Client::builder()
+        .timeout(config.influx_timeout)
+        .pool_max_idle_per_host(config.pool_size)
+        .tcp_keepalive(TCP_KEEPALIVE);
+    if let Some(_influx_ca_cert) = &config.influx_ca_cert {
+        match read_certificate(_influx_ca_cert) {
+            Ok(_certificate) => client_builder = client_builder.add_root_certificate(_certificate),
+            Err(_err) => {
+                log!(
+                    "Error: could not read CA certificate {}: {}",
+                    _influx_ca_cert,
+                    _err
+                );
+                process::exit(1);
+            }
+        }
+    }
+    if config.influx_insecure {
+        log!("Warning: not verifying the certificate of InfluxDB, only use this for testing");
+        client_builder = client_builder.danger_accept_invalid_certs(true);
+    }
+    let client = client_builder.build()?;
+
+    // let example_telegram = "\u{0}\n/KFM5KAIFA-METER\r\n\r\n1-3:0.2.8(42)\r\n0-0:1.0.0(210212094443W)\r\n0-0:96.1.1(4530303235303030303634383435373136)\r\n1-0:1.8.1(007392.132*kWh)\r\n1-0:1.8.2(007139.800*kWh)\r\n1-0:2.8.1(001795.226*kWh)\r\n1-0:2.8.2(004446.275*kWh)\r\n0-0:96.14.0(0002)\r\n1-0:1.7.0(00.131*kW)\r\n1-0:2.7.0(00.000*kW)\r\n0-0:96.7.21(00001)\r\n0-0:96.7.9(00001)\r\n1-0:99.97.0(2)(0-0:96.7.19)(181206112732W)(0000007692*s)(000101000001W)(2147483647*s)\r\n1-0:32.32.0(00000)\r\n1-0:32.36.0(00000)\r\n0-0:96.13.1()\r\n0-0:96.13.0()\r\n1-0:31.7.0(002*A)\r\n1-0:21.7.0(00.123*kW)\r\n1-0:22.7.0(00.000*kW)\r\n0-1:24.1.0(003)\r\n0-1:96.1.0(4730303331303033333930303231353136)\r\n0-1:24.2.1(210205130000W)(07025.512*m3)\r\n!8234\r\n";
+
+    // Find out about a wrong URL or an unreachable database right away instead of at the first post
+    if !config.json && !config.stdout_only && !config.obis_dump {
+        for influx_url in &config.influx_urls {
+            match ping_influx_db(&client, influx_url).await {
+                Ok(_version) => info!("Connected to InfluxDB {} at {}", _version, influx_url),
+                Err(_err) if config.no_startup_check => {
+                    log!(
+                        "Warning: could not reach InfluxDB at {}: {}",
+                        influx_url,
+                        _err
+                    );
+                }
+                Err(_err) => {
+                    log!(
+                        "Error: could not reach InfluxDB at {}: {}",
+                        influx_url,
+                        _err
+                    );
+                    process::exit(1);
+                }
+            }
+        }
+    }
+
+    let mut adapter = Adapter::new(&config, client, last_telegram.clone(), metrics);
+    if let Some(_state_file) = &config.state_file {
+        match State::load(_state_file) {
+            Ok(_state) => adapter.restore_state(_state),
+            Err(_err) => log!(
+                "Warning: could not read state file {}: {}",
+                _state_file,
+                _err
+            ),
+        }
+    }
+
+    // Telegrams are parsed and posted in a separate task, so a slow InfluxDB doesn't hold up
+    // reading the meter
+    let dropped_telegrams = adapter.stats.dropped_telegrams.clone();
+    let (mut telegrams, mut telegrams_rx) = mpsc::channel::<String>(TELEGRAM_QUEUE_SIZE);
+    let processing = tokio::spawn(async move {
+        // Lines collected with `--flush-interval` are posted and the `--state-file` is written
+        // before stopping, otherwise the signals keep their default of stopping right away
+        let finish_on_shutdown =
+            adapter.config.flush_interval.is_some() || adapter.config.state_file.is_some();
+        let shutdown = async {
+            if finish_on_shutdown {
+                shutdown_signal().await
+            } else {
+                future::pending().await
+            }
+        };
+        tokio::pin!(shutdown);
+
+        loop {
+            // The collected lines are also posted when no telegram arrives, e.g. when the meter
+            // went quiet
+            let flush_due = adapter.flush_due();
+            let flush = async {
+                match flush_due {
+                    Some(_flush_due) => {
+                        tokio::time::delay_until(tokio::time::Instant::from_std(_flush_due)).await
+                    }
+                    None => future::pending().await,
+                }
+            };
+            let telegram = tokio::select! {
+                _telegram = telegrams_rx.recv() => _telegram,
+                _ = flush => {
+                    adapter.scheduled_flush().await;
+                    continue;
+                }
+                _ = &mut shutdown => {
+                    adapter.finish().await;
+                    process::exit(0);
+                }
+            };
+            let _telegram = match telegram {
+                Some(_telegram) => _telegram,
+                None => break,
+            };
+            let span = tracing::debug_span!("telegram", bytes = _telegram.len());
+            let valid = adapter.handle_telegram(&_telegram).instrument(span).await;
+            if valid && adapter.config.once {
+                adapter.finish().await;
+                process::exit(0);
+            }
+        }
+        // The input ended, e.g. the end of `--stdin`
+        adapter.finish().await;
+    });
+
+    if config.simulate {
+        info!("Simulating a meter");
+        #[cfg(feature = "systemd")]
+        notify_systemd(sd_notify::NotifyState::Ready);
+        simulate::run(telegrams).await;
+        if let Err(_err) = processing.await {
+            log!("Processing telegrams failed: {}", _err);
+        }
+        return Ok(());
+    }
+
+    if config.stdin {
+        input::read_stdin(config.max_telegram_size, telegrams).await;
+        if let Err(_err) = processing.await {
+            log!("Processing telegrams failed: {}", _err);
+        }
+        return Ok(());
+    }
+
+    let poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(1024);
+
+    let mut rx = match &config.tcp {
+        Some(_address) => {
+            info!("Connecting to {}", _address);
+            Input::Tcp(input::connect_tcp(_address).await)
+        }
+        None => {
+            // These settings are specific to your Smart Meter
+            let serial_settings = config.serial_settings();
+
+            info!(
+                "Opening {}, serial settings: {:?}",
+                config.tty, serial_settings
+            );
+
+            // Open serial port
+            Input::Serial(
+                mio_serial::Serial::from_path(&config.tty, &serial_settings)
+                    .expect("Could not open serial port"),
+            )
+        }
+    };
+
+    rx.register(&poll, INPUT_TOKEN, ready_of_interest())
+        .unwrap();
+
+    // Older meters only transmit while the data request line is high
+    if let Input::Serial(_serial) = &mut rx {
+        if config.assert_rts || config.request_interval.is_some() {
+            request_data(_serial);
+        }
+    }
+    #[cfg(feature = "systemd")]
+    notify_systemd(sd_notify::NotifyState::Ready);
+
+    let mut next_request = config
+        .request_interval
+        .map(|_request_interval| Instant::now() + _request_interval);
+
+    let mut rx_buf = [0u8; 1024];
+    let mut framer = Framer::new(config.max_telegram_size);
+
+    let mut capture = match &config.capture {
+        Some(_path) => match Capture::open(_path, config.capture_count) {
+            Ok(_capture) => {
+                info!("Capturing telegrams to {}", _path);
+                Some(_capture)
+            }
+            Err(_err) => {
+                log!("Error: could not open capture file {}: {}", _path, _err);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // In `--once` mode give up if no valid telegram arrived before the timeout
+    let deadline = if config.once {
+        Some(Instant::now() + config.timeout)
+    } else {
+        None
+    };
+
+    // Warn when the meter stops sending data, repeated every `--stall-timeout` while it lasts
+    let mut last_data = Instant::now();
+    let mut next_stall_warning = last_data + config.stall_timeout;
+
+    // Help out when no valid telegram arrived shortly after startup, likely a setup problem
+    let mut startup_warning = Some(Instant::now() + config.startup_timeout);
+
+    // Log how fast data comes in every `--rate-every`, to diagnose flaky cables
+    let mut read_rate = ReadRate::new();
+
+    'outer: loop {
+        // Wake up for whichever comes first, the `--once` deadline, the next data request, the
+        // next stall warning or the next read rate summary
+        let next_rate_summary = config
+            .rate_every
+            .map(|_rate_every| read_rate.since() + _rate_every);
+        let poll_timeout = [
+            deadline,
+            next_request,
+            Some(next_stall_warning),
+            startup_warning,
+            next_rate_summary,
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .map(|_wake_up| _wake_up.saturating_duration_since(Instant::now()));
+        if let Err(ref e) = poll.poll(&mut events, poll_timeout) {
+            log!("poll failed: {}", e);
+            break;
+        }
+
+        if let Some(_deadline) = deadline {
+            if Instant::now() >= _deadline {
+                log!(
+                    "No valid telegram received within {} seconds",
+                    config.timeout.as_secs()
+                );
+                process::exit(1);
+            }
+        }
+
+        if let Some(_next_request) = next_request {
+            if Instant::now() >= _next_request {
+                // Pulse the data request line to ask the meter for a new telegram
+                if let Input::Serial(_serial) = &mut rx {
+                    if let Err(_err) = _serial.write_request_to_send(false) {
+                        log!("Could not clear RTS: {}", _err);
+                    }
+                    request_data(_serial);
+                }
+                next_request = config
+                    .request_interval
+                    .map(|_request_interval| Instant::now() + _request_interval);
+            }
+        }
+
+        if let Some(_startup_warning) = startup_warning {
+            if Instant::now() >= _startup_warning {
+                if last_telegram.lock().unwrap().is_none() {
+                    log_startup_hints(&config);
+                }
+                startup_warning = None;
+            }
+        }
+
+        if let Some(_next_rate_summary) = next_rate_summary {
+            if Instant::now() >= _next_rate_summary {
+                log!("Read rate: {}", read_rate.take());
+            }
+        }
+
+        if Instant::now() >= next_stall_warning {
+            log!(
+                "Warning: no data received for {} seconds",
+                last_data.elapsed().as_secs()
+            );
+            next_stall_warning = Instant::now() + config.stall_timeout;
+        }
+
+        if events.is_empty() {
+            continue;
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                INPUT_TOKEN => {
+                    let ready = event.readiness();
+                    let mut closed = if is_closed(ready) {
+                        Some(format!("event: {:?}", ready))
+                    } else {
+                        None
+                    };
+                    if closed.is_none() && ready.is_readable() {
+                        // With edge triggered events, we must perform reading until we receive a WouldBlock.
+                        // See https://docs.rs/mio/0.6/mio/struct.Poll.html for details.
+                        loop {
+                            match rx.read(&mut rx_buf) {
+                                // The other end closed the connection
+                                Ok(0) => {
+                                    closed = Some("end of stream".to_string());
+                                    break;
+                                }
+                                Ok(count) => {
+                                    last_data = Instant::now();
+                                    next_stall_warning = last_data + config.stall_timeout;
+                                    read_rate.add_bytes(count);
+
+                                    // Read a chunk of the telegram, which completes it if it
+                                    // includes the end of the "!" (end of frame) line. At a
+                                    // high baud rate a chunk can complete more than one.
+                                    for telegram in framer.push(&rx_buf[..count]) {
+                                        read_rate.add_telegram();
+                                        if let Some(_capture) = capture.as_mut() {
+                                            _capture.write(&telegram);
+                                        }
+                                        match telegrams.try_send(telegram) {
+                                            Ok(_) => {}
+                                            Err(mpsc::error::TrySendError::Full(_)) => {
+                                                log!("Warning: processing can't keep up, dropping telegram");
+                                                dropped_telegrams.fetch_add(
+                                                    1,
+                                                    std::sync::atomic::Ordering::Relaxed,
+                                                );
+                                            }
+                                            Err(mpsc::error::TrySendError::Closed(_)) => {
+                                                break 'outer;
+                                            }
+                                        }
+                                    }
+                                }
+                                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                                    break;
+                                }
+                                Err(ref e) => {
+                                    closed = Some(format!("read error: {}", e));
+                                    break;
+                                }
+                            }
+                        }
+                    }
+
+                    if let Some(_reason) = closed {
+                        match &config.tcp {
+                            Some(_address) => {
+                                log!("Connection lost due to {}, reconnecting", _reason);
+                                if let Err(_err) = rx.deregister(&poll) {
+                                    log!("Could not deregister connection: {}", _err);
+                                }
+                                rx = Input::Tcp(input::connect_tcp(_address).await);
+                                rx.register(&poll, INPUT_TOKEN, ready_of_interest())
+                                    .unwrap();
+                                // The rest of the telegram that was being received is lost
+                                framer = Framer::new(config.max_telegram_size);
+                            }
+                            None => {
+                                log!("Quitting due to {}", _reason);
+                                break 'outer;
+                            }
+                        }
+                    }
+                }
+                t => unreachable!("Unexpected token: {:?}", t),
+            }
+        }
+    }
+
+    // Let the telegrams that were already read be processed
+    drop(telegrams);
+    if let Err(_err) = processing.await {
+        log!("Processing telegrams failed: {}", _err);
+    }
+    Ok(())
+}
@@ -0,0 +1,157 @@
+use chrono::{DateTime, Duration as ChronoDuration, Timelike, Utc};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+// Time between two simulated telegrams, the same as a DSMR 5 meter
+const TELEGRAM_INTERVAL: Duration = Duration::from_secs(1);
+
+// Time between two simulated gas meter readings
+const GAS_READING_INTERVAL: i64 = 5 * 60;
+
+// Generates plausible DSMR 5 telegrams, to try the adapter without a meter
+pub struct Simulator {
+    // State of the xorshift random number generator
+    random: u64,
+    // Current usage and production in W
+    w_usage: f64,
+    w_production: f64,
+    // Accumulative usage and production per tariff in kWh
+    w_usage_accumulative: [f64; 2],
+    w_production_accumulative: [f64; 2],
+    gas_usage: f64,
+    // Latest gas meter reading as (Unix time, telegram timestamp, m3)
+    gas_reading: Option<(i64, String, f64)>,
+}
+
+impl Simulator {
+    pub fn new() -> Simulator {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|_duration| _duration.as_nanos() as u64)
+            .unwrap_or(0);
+        Simulator {
+            random: seed | 1,
+            w_usage: 400.0,
+            w_production: 0.0,
+            w_usage_accumulative: [7392.132, 7139.8],
+            w_production_accumulative: [1795.226, 4446.275],
+            gas_usage: 7025.512,
+            gas_reading: None,
+        }
+    }
+
+    // Random number between -1 and 1
+    fn random(&mut self) -> f64 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        (self.random % 2_000_001) as f64 / 1_000_000.0 - 1.0
+    }
+
+    // Telegram of the meter at `now`, in the time zone of the meter
+    pub fn telegram(&mut self, now: DateTime<Utc>) -> String {
+        let hour = now.hour();
+        let timestamp = now.format("%y%m%d%H%M%SW").to_string();
+
+        // Usage drifts around, with the occasional appliance switching on or off
+        self.w_usage = (self.w_usage + self.random() * 50.0).clamp(80.0, 4000.0);
+        if self.random() > 0.98 {
+            self.w_usage += 1500.0 * self.random();
+            self.w_usage = self.w_usage.max(80.0);
+        }
+        // Solar panels only produce during the day, with clouds passing by
+        self.w_production = if (8..18).contains(&hour) {
+            (self.w_production + self.random() * 100.0).clamp(0.0, 3000.0)
+        } else {
+            0.0
+        };
+
+        // The low tariff applies at night
+        let tariff = if (7..23).contains(&hour) { 2 } else { 1 };
+        let hours = TELEGRAM_INTERVAL.as_secs_f64() / 3600.0;
+        self.w_usage_accumulative[tariff - 1] += self.w_usage / 1000.0 * hours;
+        self.w_production_accumulative[tariff - 1] += self.w_production / 1000.0 * hours;
+
+        // Gas is used for heating now and then, and only read every 5 minutes. Telegrams aren't
+        // sent at exactly every second, so the time of a reading can't be matched exactly.
+        self.gas_usage += (self.random() + 1.0) * 0.0003;
+        let gas_reading_due = match &self.gas_reading {
+            Some((_read_at, _, _)) => now.timestamp() - _read_at >= GAS_READING_INTERVAL,
+            None => true,
+        };
+        if gas_reading_due {
+            self.gas_reading = Some((now.timestamp(), timestamp.clone(), self.gas_usage));
+        }
+        let (_, gas_timestamp, gas_reading) = self.gas_reading.clone().unwrap();
+
+        let body = format!(
+            "/SIM5\\2SIMULATED-METER\r\n\r\n\
+             1-3:0.2.8(50)\r\n\
+             0-0:1.0.0({})\r\n\
+             1-0:1.8.1({:010.3}*kWh)\r\n\
+             1-0:1.8.2({:010.3}*kWh)\r\n\
+             1-0:2.8.1({:010.3}*kWh)\r\n\
+             1-0:2.8.2({:010.3}*kWh)\r\n\
+             0-0:96.14.0({:04})\r\n\
+             1-0:1.7.0({:06.3}*kW)\r\n\
+             1-0:2.7.0({:06.3}*kW)\r\n\
+             0-1:24.1.0(003)\r\n\
+             0-1:24.2.1({})({:09.3}*m3)\r\n\
+             !",
+            timestamp,
+            self.w_usage_accumulative[0],
+            self.w_usage_accumulative[1],
+            self.w_production_accumulative[0],
+            self.w_production_accumulative[1],
+            tariff,
+            self.w_usage / 1000.0,
+            self.w_production / 1000.0,
+            gas_timestamp,
+            gas_reading
+        );
+        format!("{}{:04X}\r\n", body, crate::crc::crc16(body.as_bytes()))
+    }
+}
+
+// Feed a simulated telegram to the adapter every second, until the adapter stops
+pub async fn run(mut telegrams: mpsc::Sender<String>) {
+    let mut simulator = Simulator::new();
+    loop {
+        tokio::time::delay_for(TELEGRAM_INTERVAL).await;
+        // Meters run on Dutch time, winter time is close enough for a simulation
+        let now = Utc::now() + ChronoDuration::hours(1);
+        if telegrams.send(simulator.telegram(now)).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MeterProfile;
+    use crate::crc;
+    use crate::obis::ObisValues;
+    use crate::parse::parse_gas_usage_accumulative;
+    use futures::executor::block_on;
+
+    #[test]
+    fn simulator_reads_the_gas_meter_every_5_minutes() {
+        let mut simulator = Simulator::new();
+        let start = chrono::Utc::now();
+        let gas_reading = |_telegram: &str| {
+            assert_eq!(crc::check(_telegram, MeterProfile::Dsmr), Ok(()));
+            let obis = ObisValues::parse(_telegram);
+            block_on(parse_gas_usage_accumulative(&obis, 1)).unwrap()
+        };
+
+        let first = gas_reading(&simulator.telegram(start));
+        assert!(first.0.is_some());
+        for _secs in &[1, 60, 299] {
+            let telegram = simulator.telegram(start + chrono::Duration::seconds(*_secs));
+            assert_eq!(gas_reading(&telegram), first);
+        }
+        let next = gas_reading(&simulator.telegram(start + chrono::Duration::seconds(300)));
+        assert_eq!(next.0.unwrap() - first.0.unwrap(), 300);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::measurements::MEASUREMENTS;
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Previous values the derived measurements depend on, kept in `--state-file` across restarts so
+// e.g. a counter reset isn't missed and the gas flow rate doesn't start over
+#[derive(Default)]
+pub struct State {
+    // Last accepted value of each accumulative counter and when it was received
+    pub counters: Vec<(&'static str, SystemTime, f64)>,
+    // Last gas reading as (timestamp, m3)
+    pub gas: Option<(i64, f64)>,
+    // Last posted value of each accumulative counter, used by `--dedupe-accumulative`
+    pub last_posted: Vec<(&'static str, f64)>,
+    // Accumulative counters at the last post, used by `--post-deltas`
+    pub delta_counters: Vec<(&'static str, f64)>,
+}
+
+impl State {
+    // Load the state, a missing file is an empty state
+    pub fn load(path: &str) -> Result<State, String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(_contents) => _contents,
+            Err(_err) if _err.kind() == io::ErrorKind::NotFound => return Ok(State::default()),
+            Err(_err) => return Err(_err.to_string()),
+        };
+        let value: Value = serde_json::from_str(&contents).map_err(|_err| _err.to_string())?;
+
+        let mut state = State::default();
+        if let Some(_counters) = value["counters"].as_object() {
+            for (name, counter) in _counters {
+                if let (Some(_name), Some(_time), Some(_value)) = (
+                    measurement_name(name),
+                    counter["time"].as_u64(),
+                    counter["value"].as_f64(),
+                ) {
+                    let time = UNIX_EPOCH + Duration::from_secs(_time);
+                    state.counters.push((_name, time, _value));
+                }
+            }
+        }
+        if let (Some(_timestamp), Some(_value)) = (
+            value["gas"]["timestamp"].as_i64(),
+            value["gas"]["value"].as_f64(),
+        ) {
+            state.gas = Some((_timestamp, _value));
+        }
+        if let Some(_last_posted) = value["lastPosted"].as_object() {
+            for (name, value) in _last_posted {
+                if let (Some(_name), Some(_value)) = (measurement_name(name), value.as_f64()) {
+                    state.last_posted.push((_name, _value));
+                }
+            }
+        }
+        if let Some(_delta_counters) = value["deltaCounters"].as_object() {
+            for (name, value) in _delta_counters {
+                if let (Some(_name), Some(_value)) = (measurement_name(name), value.as_f64()) {
+                    state.delta_counters.push((_name, _value));
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    // Write the state to a temporary file first, so a crash while writing can't leave a
+    // truncated state behind
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut counters = serde_json::Map::new();
+        for (name, time, value) in &self.counters {
+            let time = time
+                .duration_since(UNIX_EPOCH)
+                .map(|_duration| _duration.as_secs())
+                .unwrap_or(0);
+            counters.insert(name.to_string(), json!({"time": time, "value": value}));
+        }
+        let mut last_posted = serde_json::Map::new();
+        for (name, value) in &self.last_posted {
+            last_posted.insert(name.to_string(), json!(value));
+        }
+        let mut delta_counters = serde_json::Map::new();
+        for (name, value) in &self.delta_counters {
+            delta_counters.insert(name.to_string(), json!(value));
+        }
+        let gas = match self.gas {
+            Some((_timestamp, _value)) => json!({"timestamp": _timestamp, "value": _value}),
+            None => Value::Null,
+        };
+        let state = json!({
+            "counters": counters,
+            "gas": gas,
+            "lastPosted": last_posted,
+            "deltaCounters": delta_counters,
+        });
+
+        let temporary_path = format!("{}.tmp", path);
+        fs::write(&temporary_path, state.to_string())?;
+        fs::rename(&temporary_path, path)
+    }
+}
+
+// Name of a known measurement, unknown names (e.g. of an older version) are ignored
+fn measurement_name(name: &str) -> Option<&'static str> {
+    MEASUREMENTS
+        .iter()
+        .map(|_measurement| _measurement.name)
+        .find(|_name| *_name == name)
+}
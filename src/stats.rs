@@ -0,0 +1,54 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Running counters of received, parsed and posted telegrams, to tell a flaky cable or an
+// unreachable database apart from a quiet meter
+#[derive(Default)]
+pub struct Stats {
+    // Complete telegrams received from the meter
+    pub telegrams: u64,
+    // Telegrams from which nothing could be parsed
+    pub invalid_telegrams: u64,
+    // Telegrams whose CRC didn't match
+    pub crc_errors: u64,
+    // Telegrams dropped because processing couldn't keep up. Shared with the task reading the
+    // meter, which is the one dropping them.
+    pub dropped_telegrams: Arc<AtomicU64>,
+    // Measurements that were expected but could not be parsed, by measurement name
+    pub parse_errors: BTreeMap<&'static str, u64>,
+    // Line protocol lines posted to InfluxDB
+    pub posts: u64,
+    // Line protocol lines InfluxDB did not accept or that could not be sent
+    pub failed_posts: u64,
+}
+
+impl Stats {
+    pub fn parse_error(&mut self, measurement: &'static str) {
+        *self.parse_errors.entry(measurement).or_insert(0) += 1;
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} telegrams ({} invalid, {} CRC errors, {} dropped), {} parse errors",
+            self.telegrams,
+            self.invalid_telegrams,
+            self.crc_errors,
+            self.dropped_telegrams.load(Ordering::Relaxed),
+            self.parse_errors.values().sum::<u64>()
+        )?;
+        if !self.parse_errors.is_empty() {
+            let parse_errors: Vec<String> = self
+                .parse_errors
+                .iter()
+                .map(|(_measurement, _count)| format!("{}: {}", _measurement, _count))
+                .collect();
+            write!(f, " ({})", parse_errors.join(", "))?;
+        }
+        write!(f, ", {} posts ({} failed)", self.posts, self.failed_posts)
+    }
+}
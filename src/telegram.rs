@@ -0,0 +1,112 @@
+use crate::FieldValue;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A value read from a telegram, stamped with the time of the telegram
+#[derive(Clone, Debug, PartialEq)]
+pub struct Measurement {
+    pub name: &'static str,
+    pub value: FieldValue,
+    // Unix timestamp of the telegram, `None` when the meter doesn't report the time
+    pub timestamp: Option<i64>,
+    // Unit the meter reported the value in, for values of which the unit can differ per meter
+    pub unit: Option<String>,
+    // M-Bus device type of the device the value was read from, e.g. 3 for a gas meter
+    pub device_type: Option<i64>,
+}
+
+// The measurements of a telegram, as passed on to the outputs (InfluxDB, stdout, JSON)
+pub struct Telegram {
+    // Unix timestamp of the telegram, `None` when the meter doesn't report the time
+    pub timestamp: Option<i64>,
+    // Unix timestamp of the time the gas meter was read, tells a stale gas reading
+    pub gas_timestamp: Option<i64>,
+    measurements: Vec<(&'static str, FieldValue)>,
+    units: Vec<(&'static str, String)>,
+    device_types: Vec<(&'static str, i64)>,
+    // Timestamps of measurements that weren't read at the time of the telegram, e.g. the mean of
+    // an earlier telegram in the posting interval
+    timestamps: Vec<(&'static str, Option<i64>)>,
+}
+
+impl Telegram {
+    pub fn new(measurements: Vec<(&'static str, FieldValue)>, timestamp: Option<i64>) -> Telegram {
+        Telegram {
+            timestamp,
+            gas_timestamp: None,
+            measurements,
+            units: Vec::new(),
+            device_types: Vec::new(),
+            timestamps: Vec::new(),
+        }
+    }
+
+    // Set the unit a measurement was reported in
+    pub fn set_unit(&mut self, name: &'static str, unit: &str) {
+        self.units.retain(|(_name, _)| *_name != name);
+        self.units.push((name, unit.to_string()));
+    }
+
+    // Set the M-Bus device type of the device a measurement was read from
+    pub fn set_device_type(&mut self, name: &'static str, device_type: i64) {
+        self.device_types.retain(|(_name, _)| *_name != name);
+        self.device_types.push((name, device_type));
+    }
+
+    // Set the time a measurement was read at, when it differs from the time of the telegram
+    pub fn set_timestamp(&mut self, name: &'static str, timestamp: Option<i64>) {
+        self.timestamps.retain(|(_name, _)| *_name != name);
+        self.timestamps.push((name, timestamp));
+    }
+
+    // Take the measurements out, e.g. to filter them before they are posted
+    pub fn take_measurements(&mut self) -> Vec<(&'static str, FieldValue)> {
+        std::mem::take(&mut self.measurements)
+    }
+
+    // Replace the measurements, the units and device types stay
+    pub fn set_measurements(&mut self, measurements: Vec<(&'static str, FieldValue)>) {
+        self.measurements = measurements;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.measurements.is_empty()
+    }
+
+    pub fn measurements(&self) -> impl Iterator<Item = Measurement> + '_ {
+        self.measurements
+            .iter()
+            .map(move |(name, value)| Measurement {
+                name,
+                value: value.clone(),
+                timestamp: self
+                    .timestamps
+                    .iter()
+                    .find(|(_name, _)| _name == name)
+                    .map_or(self.timestamp, |(_, _timestamp)| *_timestamp),
+                unit: self
+                    .units
+                    .iter()
+                    .find(|(_name, _)| _name == name)
+                    .map(|(_, _unit)| _unit.clone()),
+                device_type: self
+                    .device_types
+                    .iter()
+                    .find(|(_name, _)| _name == name)
+                    .map(|(_, _device_type)| *_device_type),
+            })
+    }
+
+    // Find a measurement by name
+    pub fn get(&self, name: &str) -> Option<Measurement> {
+        self.measurements()
+            .find(|_measurement| _measurement.name == name)
+    }
+}
+
+// Current system time as a Unix timestamp
+pub fn system_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|_duration| _duration.as_secs() as i64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,168 @@
+// Frame telegrams from the bytes read from the meter, in chunks that don't line up with the
+// telegrams
+use dsmr_influxdb_adapter::framer::Framer;
+use dsmr_influxdb_adapter::obis::ObisValues;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn frames_every_telegram_of_a_chunk() {
+    let first = fs::read("fixtures/dsmr42.txt").unwrap();
+    let second = fs::read("fixtures/fluvius.txt").unwrap();
+    let third = fs::read("fixtures/dsmr4-gas-slot.txt").unwrap();
+    let (first_start, first_end) = first.split_at(first.len() / 2);
+    let (third_start, third_end) = third.split_at(third.len() / 2);
+    let chunks = [
+        first_start.to_vec(),
+        // The end of the first telegram, all of the second and the start of the third in a
+        // single read
+        [first_end, &second, third_start].concat(),
+        third_end.to_vec(),
+    ];
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let reader = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        for chunk in &chunks {
+            stream.write_all(chunk).unwrap();
+            thread::sleep(Duration::from_millis(200));
+        }
+    });
+
+    let mut adapter = Command::new(env!("CARGO_BIN_EXE_dsmr-influxdb-adapter"))
+        .args(["--tcp", &address, "--json"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(adapter.stdout.take().unwrap());
+    let (lines, lines_rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in stdout.lines() {
+            if lines.send(line.unwrap()).is_err() {
+                return;
+            }
+        }
+    });
+    // A JSON object per telegram, the adapter keeps running so stop waiting after a while
+    let objects: Vec<String> = (0..3)
+        .map_while(|_| lines_rx.recv_timeout(Duration::from_secs(3)).ok())
+        .collect();
+    adapter.kill().unwrap();
+    adapter.wait().unwrap();
+    reader.join().unwrap();
+
+    assert_eq!(objects.len(), 3);
+    assert!(objects[0].contains("\"timestamp\":1613119483"));
+    assert!(objects[1].contains("\"timestamp\":1589284449"));
+}
+
+// Maximum telegram size of the adapter by default
+const MAX_TELEGRAM_SIZE: usize = 8192;
+
+fn fixture(name: &str) -> String {
+    fs::read_to_string(format!("fixtures/{}", name)).unwrap()
+}
+
+#[test]
+fn frames_telegrams_with_lf_only_line_endings() {
+    // As sent by bridges that strip the `\r`
+    let telegram = fixture("dsmr42.txt").replace("\r\n", "\n");
+    let mut framer = Framer::new(MAX_TELEGRAM_SIZE);
+
+    let telegrams = framer.push(telegram.as_bytes());
+
+    // The bytes are kept as received
+    assert_eq!(telegrams, vec![telegram.clone()]);
+    let obis = ObisValues::parse(&telegrams[0]);
+    assert_eq!(obis.get("1-0:1.7.0"), Ok(&["00.131*kW"][..]));
+}
+
+#[test]
+fn drops_everything_before_the_first_telegram() {
+    let telegram = fixture("dsmr42.txt");
+    // Started reading halfway a telegram, after some line noise
+    let garbage = [
+        &b"\0\xff"[..],
+        &telegram.as_bytes()[telegram.len() / 2..],
+        // Some meters send a NUL before every telegram
+        &b"\0\n"[..],
+        telegram.as_bytes(),
+    ]
+    .concat();
+    let mut framer = Framer::new(MAX_TELEGRAM_SIZE);
+
+    // Line noise outside of the ASCII range drops the chunk it is in
+    assert!(framer.push(&garbage[..2]).is_empty());
+    let telegrams = framer.push(&garbage[2..]);
+
+    assert_eq!(telegrams, vec![telegram]);
+}
+
+#[test]
+fn frames_telegrams_with_a_slash_in_a_text_message() {
+    // Only a "/" at the start of a line starts a telegram, and only a "!" there ends it
+    let telegram = fixture("dsmr42.txt").replace(
+        "0-0:96.14.0",
+        "0-0:96.13.0(Onderhoud 12/05 /storing, zie example.nl/p1!)\r\n0-0:96.14.0",
+    );
+    let mut framer = Framer::new(MAX_TELEGRAM_SIZE);
+
+    let telegrams = framer.push(telegram.as_bytes());
+
+    assert_eq!(telegrams, vec![telegram.clone()]);
+    let obis = ObisValues::parse(&telegrams[0]);
+    assert_eq!(
+        obis.get("0-0:96.13.0"),
+        Ok(&["Onderhoud 12/05 /storing, zie example.nl/p1!"][..])
+    );
+}
+
+#[test]
+fn frames_a_telegram_received_one_byte_at_a_time() {
+    let telegram = fixture("dsmr42.txt");
+    let mut framer = Framer::new(MAX_TELEGRAM_SIZE);
+
+    let telegrams: Vec<String> = telegram
+        .as_bytes()
+        .chunks(1)
+        .flat_map(|_byte| framer.push(_byte))
+        .collect();
+
+    assert_eq!(telegrams, vec![telegram]);
+}
+
+#[test]
+fn frames_the_next_telegram_after_one_exceeding_the_max_size() {
+    let telegram = fixture("dsmr42.txt");
+    let mut framer = Framer::new(MAX_TELEGRAM_SIZE);
+
+    // A telegram whose end of frame char never arrives, e.g. because of a wrong baud rate
+    let endless = format!(
+        "/ISK5\\2M550T-1012\r\n{}",
+        "1-0:1.7.0(00.131*kW)\r\n".repeat(1000)
+    );
+    assert!(endless.len() > MAX_TELEGRAM_SIZE);
+    assert!(framer.push(endless.as_bytes()).is_empty());
+
+    assert_eq!(framer.push(telegram.as_bytes()), vec![telegram]);
+}
+
+#[test]
+fn frames_the_telegrams_around_a_garbled_one() {
+    let telegram = fixture("dsmr42.txt");
+    let mut framer = Framer::new(MAX_TELEGRAM_SIZE);
+    let mut garbled = telegram.clone().into_bytes();
+    garbled[telegram.len() / 2] = 0xff;
+    let chunk = [telegram.as_bytes(), &garbled, &[0xfe], telegram.as_bytes()].concat();
+
+    let telegrams = framer.push(&chunk);
+
+    assert_eq!(telegrams, vec![telegram.clone(), telegram]);
+}
@@ -0,0 +1,82 @@
+// Run the adapter on a captured telegram against a fake Carbon server and check what it sends
+use httpmock::prelude::*;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::process::{Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+// Accept a single connection and return everything received on it once the adapter exits
+fn fake_carbon() -> (String, JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    // The adapter keeps the connection open until it exits
+    let carbon = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        received
+    });
+    (address, carbon)
+}
+
+// Feed the captured telegram to the adapter with `--stdin`
+fn run_adapter(args: &[&str]) {
+    let mut adapter = Command::new(env!("CARGO_BIN_EXE_dsmr-influxdb-adapter"))
+        .arg("--stdin")
+        .args(["--gas-stale-after", "1000000000"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = adapter.stdin.take().unwrap();
+    stdin
+        .write_all(&fs::read("fixtures/dsmr42.txt").unwrap())
+        .unwrap();
+    drop(stdin);
+    assert!(adapter.wait().unwrap().success());
+}
+
+#[test]
+fn sends_measurements_in_the_plaintext_protocol() {
+    let (address, carbon) = fake_carbon();
+
+    run_adapter(&["--graphite", &address, "--graphite-prefix", "home.energy"]);
+
+    let received = carbon.join().unwrap();
+    assert!(received.contains("home.energy.wattUsage 131 1613119483\n"));
+    assert!(received.contains("home.energy.gasUsageAccumulative 7025.512 1613119483\n"));
+    assert!(received.contains("home.energy.currentTariff 2 1613119483\n"));
+}
+
+#[test]
+fn posts_to_influxdb_next_to_graphite() {
+    let (address, carbon) = fake_carbon();
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path("/ping");
+        then.status(204);
+    });
+    let write = server.mock(|when, then| {
+        when.method(POST)
+            .path("/write")
+            .body_contains("wattUsage=131,");
+        then.status(204);
+    });
+
+    run_adapter(&[
+        "--graphite",
+        &address,
+        "--influx-url",
+        &server.url("/write?db=p1meter"),
+        "--single-measurement",
+        "p1meter",
+    ]);
+
+    write.assert_hits(1);
+    assert!(carbon
+        .join()
+        .unwrap()
+        .contains("wattUsage 131 1613119483\n"));
+}
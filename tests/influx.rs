@@ -0,0 +1,300 @@
+// Run the adapter on a captured telegram against a fake InfluxDB and check what it posts
+use httpmock::prelude::*;
+use httpmock::Mock;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Time of the telegram in `fixtures/dsmr42.txt`, 2021-02-12 09:44:43 CET
+const TIMESTAMP: i64 = 1613119483;
+
+// Feed telegram files to the adapter with `--stdin`, posting to the fake InfluxDB
+fn run_adapter(server: &MockServer, telegrams: &[&str], args: &[&str]) -> Output {
+    let mut adapter = Command::new(env!("CARGO_BIN_EXE_dsmr-influxdb-adapter"))
+        .arg("--stdin")
+        .args(["--influx-url", &server.url("/write?db=p1meter")])
+        .args(["--host", "test"])
+        // The gas reading of the captured telegrams is years old by now
+        .args(["--gas-stale-after", "1000000000"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = adapter.stdin.take().unwrap();
+    for telegram in telegrams {
+        stdin.write_all(&fs::read(telegram).unwrap()).unwrap();
+    }
+    // Closing stdin ends the input, the adapter exits once everything is posted
+    drop(stdin);
+    let output = adapter.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    output
+}
+
+// Log output of the adapter, on either stdout or stderr
+fn log(output: &Output) -> String {
+    format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    )
+}
+
+// Answer the startup check like InfluxDB does
+fn mock_ping(server: &MockServer) -> Mock<'_> {
+    server.mock(|when, then| {
+        when.method(GET).path("/ping");
+        then.status(204).header("X-Influxdb-Version", "1.8.10");
+    })
+}
+
+// Expect a post with all of these lines, as the lines of a telegram are posted together
+fn mock_lines<'a>(server: &'a MockServer, lines: &[String]) -> Mock<'a> {
+    server.mock(|mut when, then| {
+        when = when
+            .method(POST)
+            .path("/write")
+            .query_param("db", "p1meter")
+            .query_param("precision", "s");
+        for line in lines {
+            when = when.body_contains(line);
+        }
+        then.status(204);
+    })
+}
+
+#[test]
+fn posts_every_measurement_of_a_telegram() {
+    let server = MockServer::start();
+    mock_ping(&server);
+    let lines = [
+        format!("currentTariff,host=test value=2i {}", TIMESTAMP),
+        // kW converted to W
+        format!("wattUsage,host=test value=131 {}", TIMESTAMP),
+        // The sum of both tariffs
+        format!(
+            "wattUsageAccumulative,host=test value=14531.932 {}",
+            TIMESTAMP
+        ),
+        format!(
+            "wattUsageAccumulativeTariff1,host=test value=7392.132 {}",
+            TIMESTAMP
+        ),
+        format!(
+            "wattUsageAccumulativeTariff2,host=test value=7139.8 {}",
+            TIMESTAMP
+        ),
+        format!("wattProduction,host=test value=0 {}", TIMESTAMP),
+        format!(
+            "wattProductionAccumulative,host=test value=6241.501 {}",
+            TIMESTAMP
+        ),
+        format!(
+            "wattProductionAccumulativeTariff1,host=test value=1795.226 {}",
+            TIMESTAMP
+        ),
+        format!(
+            "wattProductionAccumulativeTariff2,host=test value=4446.275 {}",
+            TIMESTAMP
+        ),
+        format!(
+            "gasUsageAccumulative,host=test,unit=m3,device_type=3 value=7025.512 {}",
+            TIMESTAMP
+        ),
+        format!("wattNett,host=test value=-131 {}", TIMESTAMP),
+        format!("gridPower,host=test value=131 {}", TIMESTAMP),
+        format!(
+            "wattAccumulativeNett,host=test value=-8290.431 {}",
+            TIMESTAMP
+        ),
+        format!("failureEventCount,host=test value=2i {}", TIMESTAMP),
+        format!("lastFailureDuration,host=test value=7692i {}", TIMESTAMP),
+        format!("mbusDeviceCount,host=test value=1i {}", TIMESTAMP),
+        format!("parseCompleteness,host=test value=100 {}", TIMESTAMP),
+        format!("adapterHeartbeat,host=test value=1i {}", TIMESTAMP),
+        format!("gasStale,host=test value=0i {}", TIMESTAMP),
+    ];
+    // The drift is against the time of the test run
+    let mut lines = lines.to_vec();
+    lines.push("meterClockDrift,host=test value=".to_string());
+    let write = mock_lines(&server, &lines);
+
+    let output = run_adapter(&server, &["fixtures/dsmr42.txt"], &[]);
+
+    write.assert_hits(1);
+    assert!(!log(&output).contains("Error"), "{}", log(&output));
+}
+
+#[test]
+fn retries_posts_that_influxdb_fails() {
+    let server = MockServer::start();
+    mock_ping(&server);
+    let write = server.mock(|when, then| {
+        when.method(POST)
+            .path("/write")
+            .body_contains(format!("wattUsage,host=test value=131 {}", TIMESTAMP));
+        then.status(500);
+    });
+
+    let output = run_adapter(
+        &server,
+        &["fixtures/dsmr42.txt"],
+        &["--influx-retries", "2"],
+    );
+
+    // The lines of the telegram are posted together, the first attempt and both retries
+    write.assert_hits(3);
+    assert!(log(&output).contains("InfluxDB POST: Error Status: 500 Internal Server Error"));
+    assert!(log(&output).contains("Retrying the InfluxDB POST in 1000 ms (2/2)"));
+}
+
+#[test]
+fn does_not_retry_posts_that_influxdb_rejects() {
+    let server = MockServer::start();
+    mock_ping(&server);
+    let write = server.mock(|when, then| {
+        when.method(POST).path("/write");
+        then.status(400);
+    });
+
+    let output = run_adapter(
+        &server,
+        &["fixtures/dsmr42.txt"],
+        &["--single-measurement", "p1meter"],
+    );
+
+    // A line InfluxDB can't parse won't get better by posting it again
+    write.assert_hits(1);
+    assert!(log(&output).contains("InfluxDB POST: Error Status: 400 Bad Request"));
+    assert!(!log(&output).contains("Retrying"));
+}
+
+#[test]
+fn exits_when_influxdb_is_unreachable_at_startup() {
+    let server = MockServer::start();
+    let ping = server.mock(|when, then| {
+        when.method(GET).path("/ping");
+        then.status(404);
+    });
+
+    let output = Command::new(env!("CARGO_BIN_EXE_dsmr-influxdb-adapter"))
+        .arg("--stdin")
+        .args(["--influx-url", &server.url("/write?db=p1meter")])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    ping.assert();
+    assert!(!output.status.success());
+    assert!(log(&output).contains("could not reach InfluxDB"));
+}
+
+#[test]
+fn flush_interval_posts_all_telegrams_in_one_request() {
+    let server = MockServer::start();
+    mock_ping(&server);
+    let write = server.mock(|when, then| {
+        when.method(POST)
+            .path("/write")
+            .body_contains(format!("wattUsage,host=test value=131 {}\n", TIMESTAMP))
+            // The telegram in `fixtures/fluvius.txt`
+            .body_contains("wattUsage,host=test value=0 1589284449\n");
+        then.status(204);
+    });
+
+    // The end of the input flushes, long before the interval is over
+    run_adapter(
+        &server,
+        &["fixtures/dsmr42.txt", "fixtures/fluvius.txt"],
+        &["--flush-interval", "3600"],
+    );
+
+    write.assert_hits(1);
+}
+
+#[test]
+fn writes_the_state_file_when_the_input_ends() {
+    let server = MockServer::start();
+    mock_ping(&server);
+    server.mock(|when, then| {
+        when.method(POST).path("/write");
+        then.status(204);
+    });
+    let state_file = std::env::temp_dir().join(format!("dsmr-state-{}.json", std::process::id()));
+
+    // The state is written at the first telegram, and then only once a minute
+    run_adapter(
+        &server,
+        &["fixtures/fluvius.txt", "fixtures/dsmr42.txt"],
+        &["--state-file", state_file.to_str().unwrap()],
+    );
+
+    let state = fs::read_to_string(&state_file).unwrap();
+    fs::remove_file(&state_file).unwrap();
+    // The usage counter of the telegram in `fixtures/dsmr42.txt`
+    assert!(state.contains("\"value\":14531.932}"), "{}", state);
+}
+
+#[test]
+fn posts_telegrams_with_lf_only_line_endings() {
+    let server = MockServer::start();
+    mock_ping(&server);
+    let write = server.mock(|when, then| {
+        when.method(POST)
+            .path("/write")
+            .body_contains(format!("wattUsage,host=test value=131 {}", TIMESTAMP));
+        then.status(204);
+    });
+    // As sent by bridges that strip the `\r`, the CRC is still that of the `\r\n` telegram
+    let telegram = std::env::temp_dir().join(format!("dsmr-lf-{}.txt", std::process::id()));
+    let contents = fs::read_to_string("fixtures/dsmr42.txt").unwrap();
+    fs::write(&telegram, contents.replace("\r\n", "\n")).unwrap();
+
+    let output = run_adapter(&server, &[telegram.to_str().unwrap()], &[]);
+
+    fs::remove_file(&telegram).unwrap();
+    write.assert();
+    assert!(!log(&output).contains("CRC"), "{}", log(&output));
+}
+
+#[test]
+fn flush_interval_posts_when_no_more_telegrams_arrive() {
+    let server = MockServer::start();
+    mock_ping(&server);
+    let write = server.mock(|when, then| {
+        when.method(POST)
+            .path("/write")
+            .body_contains(format!("wattUsage,host=test value=131 {}", TIMESTAMP));
+        then.status(204);
+    });
+
+    let mut adapter = Command::new(env!("CARGO_BIN_EXE_dsmr-influxdb-adapter"))
+        .arg("--stdin")
+        .args(["--influx-url", &server.url("/write?db=p1meter")])
+        .args(["--host", "test"])
+        .args(["--flush-interval", "1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // Keep the input open, as a meter that went quiet
+    let mut stdin = adapter.stdin.take().unwrap();
+    stdin
+        .write_all(&fs::read("fixtures/dsmr42.txt").unwrap())
+        .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while write.hits() == 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(100));
+    }
+    adapter.kill().unwrap();
+    adapter.wait().unwrap();
+    drop(stdin);
+
+    write.assert_hits(1);
+}
@@ -0,0 +1,31 @@
+// Run the adapter on a captured telegram with `--obis-dump` and check what it prints
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[test]
+fn prints_every_obis_id_with_its_measurements() {
+    let mut adapter = Command::new(env!("CARGO_BIN_EXE_dsmr-influxdb-adapter"))
+        .args(["--stdin", "--obis-dump"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = adapter.stdin.take().unwrap();
+    stdin
+        .write_all(&fs::read("fixtures/dsmr42.txt").unwrap())
+        .unwrap();
+    drop(stdin);
+    let output = adapter.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let dump = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = dump.lines().collect();
+    // Known ids are followed by the measurements read from them
+    assert!(lines.contains(&"1-0:1.7.0 (00.131*kW) wattUsage"));
+    assert!(lines.contains(&"0-1:24.2.1 (210205130000W)(07025.512*m3) gasUsageAccumulative"));
+    // Ids the adapter parses separately or not at all are printed without a measurement
+    assert!(lines.contains(&"0-1:24.1.0 (003)"));
+    assert_eq!(lines[0], "1-3:0.2.8 (42)");
+}